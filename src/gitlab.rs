@@ -0,0 +1,1211 @@
+//! Client for the parts of the GitLab REST API used by the CLI.
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_API_BASE: &str = "/api/v4";
+
+/// A configured GitLab instance.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct GitLabConfig {
+    /// Name the instance is selected by, e.g. with --instance
+    pub name: String,
+    /// Base URL of the instance, e.g. `https://gitlab.example.com`
+    pub url: String,
+    /// Personal, group or project access token with the `read_api` scope
+    #[serde(default)]
+    pub token: String,
+    /// File holding the token, used instead of `token` when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    /// Several tokens to take turns with, spreading requests over their rate limits; used
+    /// instead of `token` and `token_file` when not empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+    /// Group (ID or full path) to enumerate when the token cannot list projects globally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_group: Option<String>,
+    /// Additional headers sent with every request, e.g. for an authenticating proxy
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_headers: BTreeMap<String, String>,
+    /// Maximum number of concurrent searches against this instance, used instead of the
+    /// config's `default_concurrency` unless `--concurrency` is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<u32>,
+    /// Path of the REST API under `url`, for proxies that expose it elsewhere; defaults to `/api/v4`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+    /// Projects (full paths or IDs) never searched on this instance, e.g. mirrors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_projects: Vec<String>,
+}
+
+impl GitLabConfig {
+    /// Whether `project` is on the instance's `excluded_projects` list.
+    pub fn excludes(&self, project: &Project) -> bool {
+        self.excluded_projects.iter().any(|excluded| {
+            excluded.trim() == project.id.to_string()
+                || excluded
+                    .trim_matches('/')
+                    .eq_ignore_ascii_case(&project.path_with_namespace)
+        })
+    }
+
+    /// The path of the REST API under `url`.
+    pub fn api_base(&self) -> &str {
+        self.api_base
+            .as_deref()
+            .map(|base| base.trim_end_matches('/'))
+            .unwrap_or(DEFAULT_API_BASE)
+    }
+
+    /// The token to authenticate with. A `token_file` is read on every request, so secrets
+    /// rotated in place (e.g. Vault or Kubernetes mounts) are picked up without a restart.
+    pub fn resolve_token(&self) -> Result<String> {
+        match &self.token_file {
+            Some(path) => {
+                let token = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read token file {}", path.display()))?;
+                Ok(token.trim().to_string())
+            }
+            None => Ok(self.token.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GitLabVersion {
+    pub version: String,
+    pub revision: String,
+}
+
+/// The token's own details; personal, project and group access tokens all have them.
+#[derive(Debug, Deserialize)]
+pub struct TokenInfo {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Namespace {
+    pub id: u64,
+    pub name: String,
+    pub path: String,
+    pub kind: String,
+    pub full_path: String,
+    pub parent_id: Option<u64>,
+    pub web_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Project {
+    pub id: u64,
+    pub description: Option<String>,
+    pub name: String,
+    pub name_with_namespace: String,
+    pub path: String,
+    pub path_with_namespace: String,
+    pub created_at: String,
+    pub web_url: String,
+    pub last_activity_at: String,
+    pub namespace: Namespace,
+    /// Only present in full (non-`simple`) project representations
+    #[serde(default)]
+    pub archived: bool,
+    /// Only present in full (non-`simple`) project representations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forked_from_project: Option<ForkedFromProject>,
+    /// The user's access to the project; only present in full project representations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
+}
+
+impl Project {
+    /// The user's highest access level to the project, directly or through its group.
+    pub fn access_level(&self) -> Option<u32> {
+        let permissions = self.permissions.as_ref()?;
+        [&permissions.project_access, &permissions.group_access]
+            .into_iter()
+            .flatten()
+            .map(|access| access.access_level)
+            .max()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Permissions {
+    pub project_access: Option<Access>,
+    pub group_access: Option<Access>,
+}
+
+/// A GitLab access level: 10 guest, 20 reporter, 30 developer, 40 maintainer, 50 owner.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Access {
+    pub access_level: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForkedFromProject {
+    pub id: u64,
+    pub path_with_namespace: String,
+}
+
+/// Parameters controlling which projects `GitLabClient::projects` enumerates.
+#[derive(Debug, Default, Clone)]
+pub struct ProjectListOptions {
+    /// List archived projects alongside active ones instead of skipping them
+    pub include_archived: bool,
+    /// List only archived projects; takes precedence over `include_archived`
+    pub only_archived: bool,
+    /// Request full project details (e.g. fork information) instead of the `simple` representation
+    pub full: bool,
+    /// GitLab `order_by` field, e.g. `name` or `last_activity_at`; defaults to `id`
+    pub order_by: Option<String>,
+    /// GitLab `sort` direction, `asc` or `desc`; defaults to GitLab's own default
+    pub sort: Option<String>,
+    /// Only list projects where the user has at least this access level (e.g. 30 for developer)
+    pub min_access_level: Option<u32>,
+    /// List only a group's own projects, not those of its subgroups
+    pub exclude_subgroups: bool,
+    /// Only list projects with activity after this ISO 8601 timestamp
+    pub last_activity_after: Option<String>,
+}
+
+impl ProjectListOptions {
+    /// Parameters shared by the user and group project listings.
+    fn shared_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![(
+            "order_by",
+            self.order_by.clone().unwrap_or_else(|| "id".to_string()),
+        )];
+        if let Some(sort) = &self.sort {
+            params.push(("sort", sort.clone()));
+        }
+        // GitLab lists both active and archived projects when `archived` is omitted, and
+        // only the archived ones with `archived=true`.
+        if self.only_archived {
+            params.push(("archived", "true".to_string()));
+        } else if !self.include_archived {
+            params.push(("archived", "false".to_string()));
+        }
+        if let Some(level) = self.min_access_level {
+            params.push(("min_access_level", level.to_string()));
+        }
+        if let Some(after) = &self.last_activity_after {
+            params.push(("last_activity_after", after.clone()));
+        }
+        params
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchResultRaw {
+    pub basename: String,
+    pub data: String,
+    pub path: String,
+    pub filename: String,
+    pub id: Option<u64>,
+    #[serde(rename = "ref")]
+    pub ref_field: String,
+    pub startline: u64,
+    pub project_id: u64,
+}
+
+/// A match in a project's wiki.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WikiBlobResult {
+    /// The page's path without its file extension, e.g. `guides/deploy`
+    pub basename: String,
+    pub data: String,
+    pub path: String,
+    pub filename: String,
+    pub startline: u64,
+    pub project_id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Author {
+    pub username: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MergeRequestResult {
+    pub iid: u64,
+    pub title: String,
+    pub state: String,
+    pub web_url: String,
+    pub author: Author,
+    pub source_branch: String,
+    pub target_branch: String,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub assignees: Vec<Author>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IssueResult {
+    pub iid: u64,
+    pub title: String,
+    pub state: String,
+    pub web_url: String,
+    pub author: Author,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub assignees: Vec<Author>,
+}
+
+/// Narrows issue and merge request searches by label and assignee.
+#[derive(Debug, Default, Clone)]
+pub struct WorkItemFilter {
+    /// Labels an item must all have
+    pub labels: Vec<String>,
+    /// Username an item must be assigned to
+    pub assignee: Option<String>,
+}
+
+impl WorkItemFilter {
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.assignee.is_none()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeRequestDiff {
+    pub new_path: String,
+    pub diff: String,
+}
+
+/// An entry of a repository tree listing.
+#[derive(Debug, Deserialize)]
+pub struct TreeEntry {
+    pub path: String,
+    /// `blob` for files, `tree` for directories
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+/// A run of consecutive lines last changed by the same commit.
+#[derive(Debug, Deserialize)]
+pub struct BlameRange {
+    pub commit: BlameCommit,
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlameCommit {
+    pub id: String,
+    pub author_name: String,
+    pub authored_date: String,
+}
+
+/// The commit that last changed `line` (1-based) according to a file's blame.
+pub fn blame_line(ranges: &[BlameRange], line: u64) -> Option<&BlameCommit> {
+    let mut end = 0;
+    ranges.iter().find_map(|range| {
+        end += range.lines.len() as u64;
+        (line <= end).then_some(&range.commit)
+    })
+}
+
+/// A cached page of the project listing, keyed by instance and query parameters.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedProjectPage {
+    etag: String,
+    projects: Vec<Project>,
+    #[serde(default)]
+    next_page: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProjectCache {
+    pages: HashMap<String, CachedProjectPage>,
+}
+
+/// GitLab refused a blob search because the instance's search backend does not support it.
+#[derive(Debug)]
+pub struct BlobSearchUnavailable;
+
+impl std::fmt::Display for BlobSearchUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "blob search not available on this instance; basic search only covers repo contents when advanced search is enabled"
+        )
+    }
+}
+
+impl std::error::Error for BlobSearchUnavailable {}
+
+/// GitLab accepted a search but has not finished indexing the project for it yet.
+#[derive(Debug)]
+pub struct SearchIndexPending;
+
+impl std::fmt::Display for SearchIndexPending {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GitLab is still indexing this project for search (202 Accepted); try again shortly"
+        )
+    }
+}
+
+impl std::error::Error for SearchIndexPending {}
+
+/// GitLab refused a search because the search term is shorter than its backend allows.
+#[derive(Debug)]
+pub struct SearchTermTooShort {
+    /// GitLab's own explanation
+    pub message: String,
+}
+
+impl std::fmt::Display for SearchTermTooShort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GitLab rejected the search term as too short; use a longer query ({})",
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for SearchTermTooShort {}
+
+/// Turns GitLab's 400 answers to a search into errors callers can report once for the
+/// instance rather than once per project: `SearchTermTooShort`, and `BlobSearchUnavailable`
+/// for "scope not supported" answers to blob searches.
+async fn check_search(response: reqwest::Response, scope: &str) -> Result<reqwest::Response> {
+    if response.status() == reqwest::StatusCode::ACCEPTED {
+        return Err(SearchIndexPending.into());
+    }
+    if response.status() != reqwest::StatusCode::BAD_REQUEST {
+        return Ok(response.error_for_status()?);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    // GitLab wraps its explanation as {"message": "..."}.
+    let detail = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|value| value["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string());
+    let message = body.to_lowercase();
+    if message.contains("too short")
+        || (message.contains("at least") && message.contains("character"))
+    {
+        return Err(SearchTermTooShort { message: detail }.into());
+    }
+    if scope == "blobs" && (message.contains("not supported") || message.contains("elasticsearch"))
+    {
+        return Err(BlobSearchUnavailable.into());
+    }
+    Err(anyhow::anyhow!(
+        "GitLab rejected the search (400 Bad Request): {}",
+        detail
+    ))
+}
+
+/// Identifies a token in cache keys without storing the token itself.
+fn token_fingerprint(token: &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    token.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Characters left as they are in a URL segment; everything else is percent-encoded.
+const SEGMENT_UNRESERVED: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Encodes a numeric ID, a `group/subgroup/project` path, a file path or a ref for use as
+/// a single URL segment, so characters like `#`, `?` or spaces don't end the path early.
+fn encode_id(id_or_path: &str) -> String {
+    percent_encoding::utf8_percent_encode(id_or_path, SEGMENT_UNRESERVED).to_string()
+}
+
+/// Checks that `name` and `value` form a valid HTTP header.
+pub fn parse_header(
+    name: &str,
+    value: &str,
+) -> Result<(reqwest::header::HeaderName, reqwest::header::HeaderValue)> {
+    let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(|| format!("Invalid header name '{}'", name))?;
+    let value = reqwest::header::HeaderValue::from_str(value.trim())
+        .with_context(|| format!("Invalid value for header '{}'", name))?;
+    Ok((name, value))
+}
+
+fn header_value(response: &reqwest::Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// One attempt at an API request, as reported to a `with_request_observer` callback.
+pub struct RequestRecord<'a> {
+    pub method: &'a str,
+    /// The URL with any credentials it carries redacted
+    pub url: &'a str,
+    /// The response status, if a response arrived
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub duration: Duration,
+    /// 0 for the first attempt, counting up with each retry
+    pub attempt: u32,
+}
+
+type RequestObserver = dyn Fn(&RequestRecord) + Send + Sync;
+
+/// An endpoint GitLab flagged with a `Deprecation` header, as reported to a
+/// `with_deprecation_observer` callback.
+#[derive(Debug, Clone)]
+pub struct DeprecationNotice {
+    /// The method and API path with IDs and file paths replaced by placeholders,
+    /// e.g. `GET /projects/:id/search`
+    pub endpoint: String,
+    /// The `Deprecation` value: `true`, an `@`-prefixed Unix time or an HTTP date
+    pub deprecation: String,
+    /// The `Sunset` date after which the endpoint may be removed, if announced
+    pub sunset: Option<String>,
+}
+
+type DeprecationObserver = dyn Fn(&DeprecationNotice) + Send + Sync;
+
+/// Path segments following these name a resource rather than an endpoint.
+const ID_SEGMENTS: &[(&str, &str)] = &[
+    ("projects", ":id"),
+    ("groups", ":id"),
+    ("files", ":file_path"),
+    ("commits", ":sha"),
+    ("merge_requests", ":iid"),
+    ("issues", ":iid"),
+];
+
+/// `url`'s path below the API base, with resource IDs replaced so every request to one
+/// endpoint reads the same, e.g. `/projects/:id/repository/files/:file_path/raw`.
+fn endpoint_pattern(config: &GitLabConfig, url: &reqwest::Url) -> String {
+    let base = config.api_base();
+    let path = url.path();
+    let path = path
+        .find(base)
+        .map_or(path, |start| &path[start + base.len()..]);
+    let mut pattern = String::new();
+    let mut previous = "";
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let placeholder = ID_SEGMENTS
+            .iter()
+            .find(|(parent, _)| *parent == previous)
+            .map(|(_, placeholder)| *placeholder)
+            .or_else(|| segment.bytes().all(|b| b.is_ascii_digit()).then_some(":id"));
+        pattern.push('/');
+        pattern.push_str(placeholder.unwrap_or(segment));
+        previous = segment;
+    }
+    pattern
+}
+
+fn deprecation_notice(
+    config: &GitLabConfig,
+    request: &reqwest::Request,
+    response: &reqwest::Response,
+) -> Option<DeprecationNotice> {
+    let deprecation = header_value(response, "Deprecation")?;
+    if deprecation.trim().eq_ignore_ascii_case("false") {
+        return None;
+    }
+    Some(DeprecationNotice {
+        endpoint: format!(
+            "{} {}",
+            request.method(),
+            endpoint_pattern(config, request.url())
+        ),
+        deprecation: deprecation.trim().to_string(),
+        sunset: header_value(response, "Sunset").map(|sunset| sunset.trim().to_string()),
+    })
+}
+
+/// Query parameters that carry credentials, redacted wherever URLs are shown or logged.
+const CREDENTIAL_PARAMS: &[&str] = &["private_token", "access_token", "job_token"];
+
+/// `url` with embedded passwords and credential query parameters replaced by `REDACTED`.
+fn redacted_url(url: &reqwest::Url) -> reqwest::Url {
+    let mut url = url.clone();
+    if url.password().is_some() {
+        url.set_password(Some("REDACTED")).ok();
+    }
+    if url
+        .query_pairs()
+        .any(|(key, _)| CREDENTIAL_PARAMS.contains(&key.as_ref()))
+    {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, value)| {
+                let value = if CREDENTIAL_PARAMS.contains(&key.as_ref()) {
+                    "REDACTED".to_string()
+                } else {
+                    value.into_owned()
+                };
+                (key.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url
+}
+
+/// A GitLab instance together with the HTTP client used to talk to it.
+#[derive(Clone)]
+pub struct GitLabClient {
+    client: reqwest::Client,
+    config: GitLabConfig,
+    project_cache_path: Option<PathBuf>,
+    log_requests: bool,
+    retries: u32,
+    listing_progress: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+    request_observer: Option<Arc<RequestObserver>>,
+    deprecation_observer: Option<Arc<DeprecationObserver>>,
+    blob_search_params: Vec<(String, String)>,
+    search_type: Option<String>,
+    work_item_filter: WorkItemFilter,
+    /// Shared by clones so concurrent requests take turns with the configured `tokens`
+    next_token: Arc<AtomicUsize>,
+}
+
+impl GitLabClient {
+    pub fn new(client: reqwest::Client, config: GitLabConfig) -> Self {
+        Self {
+            client,
+            config,
+            project_cache_path: None,
+            log_requests: false,
+            retries: 0,
+            listing_progress: None,
+            request_observer: None,
+            deprecation_observer: None,
+            blob_search_params: Vec::new(),
+            search_type: None,
+            work_item_filter: WorkItemFilter::default(),
+            next_token: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Calls `report` with the number of projects listed so far as each listing page arrives.
+    pub fn with_listing_progress(mut self, report: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.listing_progress = Some(Arc::new(report));
+        self
+    }
+
+    /// Only finds issues and merge requests matching `filter`. The search API cannot filter
+    /// them, so filtered searches go through the issue and merge request listings instead.
+    pub fn with_work_item_filter(mut self, filter: WorkItemFilter) -> Self {
+        self.work_item_filter = filter;
+        self
+    }
+
+    /// Calls `observe` after every request attempt with its outcome and duration, e.g. to
+    /// keep an audit log.
+    pub fn with_request_observer(
+        mut self,
+        observe: impl Fn(&RequestRecord) + Send + Sync + 'static,
+    ) -> Self {
+        self.request_observer = Some(Arc::new(observe));
+        self
+    }
+
+    /// Calls `observe` for every response whose endpoint GitLab flags as deprecated.
+    pub fn with_deprecation_observer(
+        mut self,
+        observe: impl Fn(&DeprecationNotice) + Send + Sync + 'static,
+    ) -> Self {
+        self.deprecation_observer = Some(Arc::new(observe));
+        self
+    }
+
+    /// Prints each request's method and URL to stderr before sending it.
+    pub fn with_request_logging(mut self, enabled: bool) -> Self {
+        self.log_requests = enabled;
+        self
+    }
+
+    /// Retries requests up to `retries` times when they time out, cannot connect, or GitLab
+    /// answers 429 or a server error, waiting longer between each attempt.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Appends extra query parameters to code (blob) search requests, for search features
+    /// without a dedicated option.
+    pub fn with_blob_search_params(mut self, params: Vec<(String, String)>) -> Self {
+        self.blob_search_params = params;
+        self
+    }
+
+    /// Asks GitLab for a specific search implementation (`basic`, `advanced`) instead of
+    /// letting the instance pick.
+    pub fn with_search_type(mut self, search_type: Option<String>) -> Self {
+        self.search_type = search_type;
+        self
+    }
+
+    /// Revalidates project listing pages against the ETag cache stored at `path`.
+    pub fn with_project_cache(mut self, path: PathBuf) -> Self {
+        self.project_cache_path = Some(path);
+        self
+    }
+
+    pub fn config(&self) -> &GitLabConfig {
+        &self.config
+    }
+
+    fn get(&self, path: &str) -> Result<reqwest::RequestBuilder> {
+        self.get_with_token(path, &self.token()?)
+    }
+
+    fn get_with_token(&self, path: &str, token: &str) -> Result<reqwest::RequestBuilder> {
+        let mut request = self
+            .client
+            .get(format!(
+                "{}{}{}",
+                self.config.url,
+                self.config.api_base(),
+                path
+            ))
+            .header("PRIVATE-TOKEN", token);
+        for (name, value) in &self.config.extra_headers {
+            let (name, value) = parse_header(name, value).with_context(|| {
+                format!(
+                    "Invalid extra header '{}' for instance '{}'",
+                    name, self.config.name
+                )
+            })?;
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+
+    /// The token for the next request, rotating through `tokens` when several are configured.
+    fn token(&self) -> Result<String> {
+        match self.config.tokens.as_slice() {
+            [] => self.config.resolve_token(),
+            tokens => {
+                let turn = self.next_token.fetch_add(1, Ordering::Relaxed);
+                Ok(tokens[turn % tokens.len()].clone())
+            }
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let request = request.build()?;
+        let mut attempt = 0;
+        loop {
+            if self.log_requests {
+                // The token travels in a header; strip any credentials embedded in the URL too.
+                eprintln!("{} {}", request.method(), redacted_url(request.url()));
+            }
+            // Requests are bodiless GETs, so they can always be cloned for another attempt.
+            let attempt_request = request.try_clone().context("Request cannot be retried")?;
+            let started = Instant::now();
+            let outcome = self.client.execute(attempt_request).await;
+            if let Some(observe) = &self.request_observer {
+                let url = redacted_url(request.url());
+                // reqwest's messages quote the URL as sent.
+                let (status, error) = match &outcome {
+                    Ok(response) => (Some(response.status().as_u16()), None),
+                    Err(e) => (
+                        e.status().map(|s| s.as_u16()),
+                        Some(e.to_string().replace(request.url().as_str(), url.as_str())),
+                    ),
+                };
+                observe(&RequestRecord {
+                    method: request.method().as_str(),
+                    url: url.as_str(),
+                    status,
+                    error,
+                    duration: started.elapsed(),
+                    attempt,
+                });
+            }
+            if let (Some(observe), Ok(response)) = (&self.deprecation_observer, &outcome) {
+                if let Some(notice) = deprecation_notice(&self.config, &request, response) {
+                    observe(&notice);
+                }
+            }
+            let retryable = match &outcome {
+                Ok(response) => {
+                    response.status().is_server_error()
+                        || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => e.is_timeout() || e.is_connect(),
+            };
+            if !retryable || attempt >= self.retries {
+                return Ok(outcome?);
+            }
+            attempt += 1;
+            tokio::time::sleep(Duration::from_millis(500 << (attempt - 1).min(6))).await;
+        }
+    }
+
+    pub async fn version(&self) -> Result<GitLabVersion> {
+        let response = self.send(self.get("/version")?).await?.error_for_status()?;
+
+        let version: GitLabVersion = response.json().await?;
+        Ok(version)
+    }
+
+    /// Describes the token making the requests. Needs GitLab 15.5 or later.
+    pub async fn token_info(&self) -> Result<TokenInfo> {
+        let response = self
+            .send(self.get("/personal_access_tokens/self")?)
+            .await?
+            .error_for_status()?;
+
+        Ok(response.json().await?)
+    }
+
+    /// Whether the instance has advanced (Elasticsearch) search enabled. Without it GitLab
+    /// rejects instance-wide blob searches with 400 Bad Request. Tokens that may not search
+    /// the whole instance (401 or 403) are treated as not having it either.
+    pub async fn supports_advanced_search(&self) -> Result<bool> {
+        let response = self
+            .send(self.get("/search")?.query(&[
+                ("scope", "blobs"),
+                ("search", "gitlab"),
+                ("per_page", "1"),
+            ]))
+            .await?;
+
+        if matches!(
+            response.status(),
+            reqwest::StatusCode::BAD_REQUEST
+                | reqwest::StatusCode::UNAUTHORIZED
+                | reqwest::StatusCode::FORBIDDEN
+        ) {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Lists the projects the token's user is a member of.
+    pub async fn projects(&self, options: &ProjectListOptions) -> Result<Vec<Project>> {
+        let mut params = vec![
+            ("simple", (!options.full).to_string()),
+            ("membership", "true".to_string()),
+        ];
+        params.extend(options.shared_params());
+        self.paginate_projects("/projects", params).await
+    }
+
+    /// Lists the projects of a group (by ID or full path), including its subgroups.
+    /// Unlike `projects`, this works with group access tokens.
+    pub async fn group_projects(
+        &self,
+        group: &str,
+        options: &ProjectListOptions,
+    ) -> Result<Vec<Project>> {
+        let mut params = vec![
+            ("simple", (!options.full).to_string()),
+            (
+                "include_subgroups",
+                (!options.exclude_subgroups).to_string(),
+            ),
+        ];
+        params.extend(options.shared_params());
+        self.paginate_projects(&format!("/groups/{}/projects", encode_id(group)), params)
+            .await
+    }
+
+    async fn paginate_projects(
+        &self,
+        path: &str,
+        params: Vec<(&str, String)>,
+    ) -> Result<Vec<Project>> {
+        let mut all_projects = Vec::new();
+        let mut page = 1;
+        let per_page = 50;
+        let mut cache = self.load_project_cache();
+
+        loop {
+            let mut params = params.clone();
+            params.push(("per_page", per_page.to_string()));
+            params.push(("page", page.to_string()));
+            // Tokens can see different projects, so with several tokens taking turns each
+            // page is cached per token.
+            let token = self.token()?;
+            let cache_key = format!(
+                "{}|{:016x}|{}{}{}|{}",
+                self.config.name,
+                token_fingerprint(&token),
+                self.config.url,
+                self.config.api_base(),
+                path,
+                params
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<_>>()
+                    .join("&")
+            );
+
+            let mut request = self.get_with_token(path, &token)?.query(&params);
+            if let Some(cached) = cache.pages.get(&cache_key) {
+                request = request.header(reqwest::header::IF_NONE_MATCH, &cached.etag);
+            }
+
+            let mut response = self.send(request).await?;
+            let cached = cache.pages.get(&cache_key);
+            // Without the page to reuse (e.g. a proxy answered for a validator we did not
+            // send), ask for it in full rather than ending the listing on an empty page.
+            if response.status() == reqwest::StatusCode::NOT_MODIFIED && cached.is_none() {
+                response = self
+                    .send(self.get_with_token(path, &token)?.query(&params))
+                    .await?;
+                if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+                    return Err(anyhow::anyhow!(
+                        "GitLab answered 304 Not Modified to an unconditional request for {}",
+                        path
+                    ));
+                }
+            }
+            let (projects, next_page): (Vec<Project>, Option<String>) =
+                match cached.filter(|_| response.status() == reqwest::StatusCode::NOT_MODIFIED) {
+                    Some(cached) => (cached.projects.clone(), cached.next_page.clone()),
+                    None => {
+                        let response = response.error_for_status()?;
+                        let etag = header_value(&response, reqwest::header::ETAG.as_str());
+                        let next_page = header_value(&response, "x-next-page");
+                        let projects: Vec<Project> = response.json().await?;
+                        match etag {
+                            Some(etag) => {
+                                cache.pages.insert(
+                                    cache_key,
+                                    CachedProjectPage {
+                                        etag,
+                                        projects: projects.clone(),
+                                        next_page: next_page.clone(),
+                                    },
+                                );
+                            }
+                            None => {
+                                cache.pages.remove(&cache_key);
+                            }
+                        }
+                        (projects, next_page)
+                    }
+                };
+
+            if projects.is_empty() {
+                break;
+            }
+
+            all_projects.extend(projects);
+            if let Some(report) = &self.listing_progress {
+                report(all_projects.len());
+            }
+
+            // GitLab sends an empty X-Next-Page on the last page. Without the header
+            // (e.g. behind some proxies) keep going until an empty page comes back.
+            match next_page.as_deref().map(str::trim) {
+                Some("") => break,
+                Some(next) => {
+                    page = next
+                        .parse()
+                        .with_context(|| format!("Invalid X-Next-Page header '{}'", next))?
+                }
+                None => page += 1,
+            }
+        }
+
+        if let Err(e) = self.save_project_cache(&cache) {
+            eprintln!("Warning: {}", e);
+        }
+
+        Ok(all_projects)
+    }
+
+    /// Loads the project cache, starting over with an empty one if it is missing or unreadable.
+    fn load_project_cache(&self) -> ProjectCache {
+        self.project_cache_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the cache through a temporary file, so instances listed in parallel replace
+    /// it whole instead of interleaving their writes.
+    fn save_project_cache(&self, cache: &ProjectCache) -> Result<()> {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+        if let Some(path) = &self.project_cache_path {
+            let cache_json = serde_json::to_string(cache)?;
+            let tmp_path = path.with_extension(format!(
+                "{}.{}.tmp",
+                std::process::id(),
+                WRITES.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::write(&tmp_path, cache_json).context("Failed to write project cache")?;
+            std::fs::rename(&tmp_path, path).context("Failed to write project cache")?;
+        }
+        Ok(())
+    }
+
+    pub async fn project(&self, id_or_path: &str) -> Result<Project> {
+        let response = self
+            .send(self.get(&format!("/projects/{}", encode_id(id_or_path)))?)
+            .await?
+            .error_for_status()?;
+
+        let project: Project = response.json().await?;
+        Ok(project)
+    }
+
+    /// Whether the project's repository contains the given commit (SHA, branch or tag).
+    pub async fn has_commit(&self, project_id: u64, sha: &str) -> Result<bool> {
+        let response = self
+            .send(self.get(&format!(
+                "/projects/{}/repository/commits/{}",
+                project_id,
+                encode_id(sha)
+            ))?)
+            .await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Downloads a file's contents at the given ref.
+    pub async fn raw_file(&self, project_id: u64, path: &str, git_ref: &str) -> Result<String> {
+        let response = self
+            .send(
+                self.get(&format!(
+                    "/projects/{}/repository/files/{}/raw",
+                    project_id,
+                    encode_id(path)
+                ))?
+                .query(&[("ref", git_ref)]),
+            )
+            .await?
+            .error_for_status()?;
+
+        Ok(response.text().await?)
+    }
+
+    /// Lists the paths of all files under `path` in the default branch, recursively.
+    pub async fn tree_files(&self, project_id: u64, path: &str) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut page = 1;
+        loop {
+            let response = self
+                .send(
+                    self.get(&format!("/projects/{}/repository/tree", project_id))?
+                        .query(&[
+                            ("path", path),
+                            ("recursive", "true"),
+                            ("per_page", "100"),
+                            ("page", &page.to_string()),
+                        ]),
+                )
+                .await?
+                .error_for_status()?;
+            let next_page = header_value(&response, "x-next-page");
+            let entries: Vec<TreeEntry> = response.json().await?;
+            if entries.is_empty() {
+                break;
+            }
+            files.extend(
+                entries
+                    .into_iter()
+                    .filter(|entry| entry.kind == "blob")
+                    .map(|entry| entry.path),
+            );
+            match next_page.as_deref().map(str::trim) {
+                Some("") => break,
+                Some(next) => {
+                    page = next
+                        .parse()
+                        .with_context(|| format!("Invalid X-Next-Page header '{}'", next))?
+                }
+                None => page += 1,
+            }
+        }
+        Ok(files)
+    }
+
+    /// Fetches the blame of a file at the given ref, in line order.
+    pub async fn blame(
+        &self,
+        project_id: u64,
+        path: &str,
+        git_ref: &str,
+    ) -> Result<Vec<BlameRange>> {
+        let response = self
+            .send(
+                self.get(&format!(
+                    "/projects/{}/repository/files/{}/blame",
+                    project_id,
+                    encode_id(path)
+                ))?
+                .query(&[("ref", git_ref)]),
+            )
+            .await?
+            .error_for_status()?;
+
+        Ok(response.json().await?)
+    }
+
+    /// A search request within a project, in the configured search type if any.
+    fn project_search(
+        &self,
+        project_id: u64,
+        scope: &str,
+        query: &str,
+        per_page: u32,
+    ) -> Result<reqwest::RequestBuilder> {
+        let mut request = self
+            .get(&format!("/projects/{}/search", project_id))?
+            .query(&[("scope", scope), ("search", query)])
+            .query(&[("per_page", per_page)]);
+        if let Some(search_type) = &self.search_type {
+            request = request.query(&[("search_type", search_type)]);
+        }
+        Ok(request)
+    }
+
+    pub async fn search_blobs(&self, project_id: u64, query: &str) -> Result<Vec<SearchResultRaw>> {
+        let response = self
+            .send(
+                self.project_search(project_id, "blobs", query, 100)?
+                    .query(&self.blob_search_params),
+            )
+            .await?;
+        let response = check_search(response, "blobs").await?;
+
+        let results: Vec<SearchResultRaw> = response.json().await?;
+        Ok(results)
+    }
+
+    /// Counts matches in a project without downloading them, by reading the `X-Total`
+    /// header of a single-item page. GitLab omits the header for very large result sets.
+    pub async fn count_matches(
+        &self,
+        project_id: u64,
+        query: &str,
+        scope: &str,
+    ) -> Result<Option<u64>> {
+        let mut request = self.project_search(project_id, scope, query, 1)?;
+        if scope == "blobs" {
+            request = request.query(&self.blob_search_params);
+        }
+        let response = self.send(request).await?;
+        let response = check_search(response, scope).await?;
+
+        let total = header_value(&response, "x-total").and_then(|value| value.parse().ok());
+        Ok(total)
+    }
+
+    pub async fn search_merge_requests(
+        &self,
+        project_id: u64,
+        query: &str,
+    ) -> Result<Vec<MergeRequestResult>> {
+        if !self.work_item_filter.is_empty() {
+            return self
+                .filtered_work_items(project_id, "merge_requests", query)
+                .await;
+        }
+        let response = self
+            .send(self.project_search(project_id, "merge_requests", query, 100)?)
+            .await?;
+        let response = check_search(response, "merge_requests").await?;
+
+        let results: Vec<MergeRequestResult> = response.json().await?;
+        Ok(results)
+    }
+
+    pub async fn search_wiki_blobs(
+        &self,
+        project_id: u64,
+        query: &str,
+    ) -> Result<Vec<WikiBlobResult>> {
+        let response = self
+            .send(self.project_search(project_id, "wiki_blobs", query, 100)?)
+            .await?;
+        let response = check_search(response, "wiki_blobs").await?;
+
+        let results: Vec<WikiBlobResult> = response.json().await?;
+        Ok(results)
+    }
+
+    pub async fn search_issues(&self, project_id: u64, query: &str) -> Result<Vec<IssueResult>> {
+        if !self.work_item_filter.is_empty() {
+            return self.filtered_work_items(project_id, "issues", query).await;
+        }
+        let response = self
+            .send(self.project_search(project_id, "issues", query, 100)?)
+            .await?;
+        let response = check_search(response, "issues").await?;
+
+        let results: Vec<IssueResult> = response.json().await?;
+        Ok(results)
+    }
+
+    /// Searches a project's `issues` or `merge_requests` listing, which unlike the search
+    /// API filters by label and assignee.
+    async fn filtered_work_items<T: serde::de::DeserializeOwned>(
+        &self,
+        project_id: u64,
+        kind: &str,
+        query: &str,
+    ) -> Result<Vec<T>> {
+        let filter = &self.work_item_filter;
+        let mut request = self
+            .get(&format!("/projects/{}/{}", project_id, kind))?
+            .query(&[("search", query), ("scope", "all"), ("per_page", "100")]);
+        if !filter.labels.is_empty() {
+            request = request.query(&[("labels", filter.labels.join(","))]);
+        }
+        if let Some(assignee) = &filter.assignee {
+            request = request.query(&[("assignee_username", assignee)]);
+        }
+        let response = self.send(request).await?.error_for_status()?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn merge_request_diffs(
+        &self,
+        project_id: u64,
+        merge_request_iid: u64,
+    ) -> Result<Vec<MergeRequestDiff>> {
+        let response = self
+            .send(
+                self.get(&format!(
+                    "/projects/{}/merge_requests/{}/diffs",
+                    project_id, merge_request_iid
+                ))?
+                .query(&[("per_page", "100")]),
+            )
+            .await?
+            .error_for_status()?;
+
+        let diffs: Vec<MergeRequestDiff> = response.json().await?;
+        Ok(diffs)
+    }
+}
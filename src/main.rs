@@ -1,539 +1,6016 @@
 #[allow(dead_code)]
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
 use config::{Config, File};
 use futures::future::join_all;
-use indicatif::{ProgressBar, ProgressStyle};
+use futures::stream::{self, StreamExt};
+use futures::FutureExt;
+use gitlab_search_cli::gitlab::{
+    blame_line, parse_header, Author, BlameRange, BlobSearchUnavailable, DeprecationNotice,
+    GitLabClient, GitLabConfig, IssueResult, MergeRequestResult, Project, ProjectListOptions,
+    RequestRecord, SearchIndexPending, SearchResultRaw, SearchTermTooShort, WikiBlobResult,
+    WorkItemFilter,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{IsTerminal, Read as _};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
-}
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Configure GitLab instances
-    Config {
-        /// GitLab instance name
-        #[arg(short, long)]
-        name: Option<String>,
-
-        /// GitLab URL
-        #[arg(short, long)]
-        url: Option<String>,
-
-        /// GitLab API token
-        #[arg(short, long)]
-        token: Option<String>,
-
-        /// List all configured GitLab instances
-        #[arg(short, long)]
-        list: bool,
-    },
-    /// Search for code in GitLab projects
-    Search {
-        /// Search query
-        #[arg(short, long)]
-        query: String,
-
-        /// GitLab instance name (from config)
-        #[arg(short, long)]
-        instance: Option<String>,
-
-        /// Project ID or path with namespace
-        #[arg(short, long)]
-        project: Option<String>,
-
-        /// Search in all projects (may be slow)
-        #[arg(short, long)]
-        all_projects: bool,
-    },
-    /// List projects in GitLab instance
-    Projects {
-        /// GitLab instance name (from config)
-        #[arg(short, long)]
-        instance: Option<String>,
-
-        /// Include archived projects
-        #[arg(short, long)]
-        archived: bool,
-    },
+    #[command(flatten)]
+    global: GlobalArgs,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct GitLabConfig {
-    name: String,
-    url: String,
-    token: String,
+/// Options shared by every command.
+#[derive(Args, Clone)]
+struct GlobalArgs {
+    /// User-Agent header sent with every API request
+    #[arg(long, global = true)]
+    user_agent: Option<String>,
+
+    /// Use a separate set of instances and caches stored under `<config dir>/<profile>`
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Maximum number of idle connections kept open per host for reuse
+    #[arg(long, global = true, default_value_t = DEFAULT_POOL_MAX_IDLE)]
+    pool_max_idle: usize,
+
+    /// Extra header sent with every request, as "Name: Value" (repeatable)
+    #[arg(long = "header", global = true, value_name = "NAME: VALUE", value_parser = parse_header_arg)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Print the method and URL of every API request to stderr, e.g. to reproduce it with curl
+    #[arg(long, global = true)]
+    print_requests: bool,
+
+    /// Append a JSON line per API request, searched project and search outcome to this
+    /// file, e.g. as an audit log of scheduled searches
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Interval in seconds for TCP keep-alive probes on open connections (0 disables them)
+    #[arg(long, global = true, default_value_t = DEFAULT_TCP_KEEPALIVE_SECS)]
+    tcp_keepalive: u64,
+
+    /// Don't ask for gzip, brotli or deflate compressed responses, e.g. behind a proxy
+    /// that mangles them
+    #[arg(long, global = true)]
+    no_compression: bool,
+
+    /// Seconds before an API request is abandoned [default: config `default_timeout`, else none]
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+
+    /// Times a request is retried after a timeout, connection error, 429 or server error
+    /// [default: config `default_retries`, else 0]
+    #[arg(long, global = true)]
+    retries: Option<u32>,
+
+    /// Fail if the config file does not exist instead of creating an empty one
+    #[arg(long, global = true)]
+    no_config_autocreate: bool,
+
+    /// On failure, print the full chain of causes on separate lines, and the backtrace
+    /// when RUST_BACKTRACE is set, instead of a single-line message
+    #[arg(long, global = true)]
+    verbose_errors: bool,
+
+    /// Don't check once a day for a newer release (also disabled by setting
+    /// GITLAB_SEARCH_CLI_NO_UPDATE_CHECK)
+    #[arg(long, global = true)]
+    no_update_check: bool,
+
+    /// Don't send long search results and project lists through `$PAGER` (or `less`)
+    #[arg(long, global = true)]
+    no_pager: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AppConfig {
-    gitlab_instances: Vec<GitLabConfig>,
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable, colorized text
+    #[default]
+    Text,
+    /// Machine-readable JSON
+    Json,
+    /// A Markdown document, e.g. for pasting into GitLab issues (search only)
+    Markdown,
 }
 
-#[derive(Debug, Deserialize)]
-struct GitLabVersion {
-    version: String,
-    revision: String,
+impl OutputFormat {
+    fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+            OutputFormat::Markdown => "markdown",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct Namespace {
-    id: u64,
-    name: String,
-    path: String,
-    kind: String,
-    full_path: String,
-    parent_id: Option<u64>,
-    web_url: String,
+#[derive(Clone, Copy, ValueEnum)]
+enum ProjectSort {
+    /// Most recently active first
+    Activity,
+    /// Alphabetically by full project name
+    Name,
+    /// Ascending project ID
+    Id,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
-struct Project {
-    id: u64,
-    description: Option<String>,
-    name: String,
-    name_with_namespace: String,
-    path: String,
-    path_with_namespace: String,
-    created_at: String,
-    web_url: String,
-    last_activity_at: String,
-    namespace: Namespace,
+/// A GitLab role, as the minimum access a project must grant.
+#[derive(Clone, Copy, ValueEnum)]
+enum AccessRole {
+    Guest,
+    Reporter,
+    Developer,
+    Maintainer,
+    Owner,
 }
 
-#[derive(Debug, Deserialize)]
-struct SearchResult {
-    basename: String,
-    data: String,
-    path: String,
-    filename: String,
-    id: Option<u64>,
-    ref_field: String,
-    startline: u64,
-    project_id: u64,
+impl AccessRole {
+    fn access_level(self) -> u32 {
+        match self {
+            AccessRole::Guest => 10,
+            AccessRole::Reporter => 20,
+            AccessRole::Developer => 30,
+            AccessRole::Maintainer => 40,
+            AccessRole::Owner => 50,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SearchResultRaw {
-    basename: String,
-    data: String,
-    path: String,
-    filename: String,
-    id: Option<u64>,
-    #[serde(rename = "ref")]
-    ref_field: String,
-    startline: u64,
-    project_id: u64,
+/// Server-side ordering of the project listing.
+#[derive(Clone, Copy, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum ProjectOrder {
+    Id,
+    Name,
+    CreatedAt,
+    LastActivityAt,
 }
 
-async fn get_config() -> Result<AppConfig> {
-    let config_dir = dirs::config_dir()
-        .context("Could not find config directory")?
-        .join("gitlab-search-cli");
+impl ProjectOrder {
+    fn api_name(self) -> &'static str {
+        match self {
+            ProjectOrder::Id => "id",
+            ProjectOrder::Name => "name",
+            ProjectOrder::CreatedAt => "created_at",
+            ProjectOrder::LastActivityAt => "last_activity_at",
+        }
+    }
+}
 
-    std::fs::create_dir_all(&config_dir).context("Failed to create config directory")?;
+/// A project field that `projects --columns` can display.
+#[derive(Clone, Copy, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum ProjectColumn {
+    Id,
+    Name,
+    PathWithNamespace,
+    WebUrl,
+    LastActivityAt,
+    Namespace,
+}
 
-    let config_path = config_dir.join("config.json");
+impl ProjectColumn {
+    fn name(self) -> &'static str {
+        match self {
+            ProjectColumn::Id => "id",
+            ProjectColumn::Name => "name",
+            ProjectColumn::PathWithNamespace => "path_with_namespace",
+            ProjectColumn::WebUrl => "web_url",
+            ProjectColumn::LastActivityAt => "last_activity_at",
+            ProjectColumn::Namespace => "namespace",
+        }
+    }
 
-    if !config_path.exists() {
-        let default_config = AppConfig {
-            gitlab_instances: Vec::new(),
-        };
-        let config_json = serde_json::to_string_pretty(&default_config)?;
-        std::fs::write(&config_path, config_json).context("Failed to write default config")?;
+    fn value(self, project: &Project) -> serde_json::Value {
+        match self {
+            ProjectColumn::Id => project.id.into(),
+            ProjectColumn::Name => project.name.as_str().into(),
+            ProjectColumn::PathWithNamespace => project.path_with_namespace.as_str().into(),
+            ProjectColumn::WebUrl => project.web_url.as_str().into(),
+            ProjectColumn::LastActivityAt => project.last_activity_at.as_str().into(),
+            ProjectColumn::Namespace => project.namespace.full_path.as_str().into(),
+        }
     }
+}
 
-    let config = Config::builder()
-        .add_source(File::from(config_path))
-        .build()?;
+#[derive(Clone, Copy, ValueEnum)]
+enum SortDirection {
+    Asc,
+    Desc,
+}
 
-    let app_config: AppConfig = config.try_deserialize()?;
-    Ok(app_config)
+/// Who owns a project's namespace.
+#[derive(Clone, Copy, ValueEnum)]
+enum NamespaceKind {
+    /// A user's personal namespace
+    User,
+    /// A group or subgroup
+    Group,
 }
 
-async fn save_config(config: &AppConfig) -> Result<()> {
-    let config_dir = dirs::config_dir()
-        .context("Could not find config directory")?
-        .join("gitlab-search-cli");
+impl NamespaceKind {
+    fn api_name(self) -> &'static str {
+        match self {
+            NamespaceKind::User => "user",
+            NamespaceKind::Group => "group",
+        }
+    }
 
-    let config_path = config_dir.join("config.json");
-    let config_json = serde_json::to_string_pretty(&config)?;
-    std::fs::write(&config_path, config_json).context("Failed to write config")?;
-    Ok(())
+    fn owns(self, project: &Project) -> bool {
+        project.namespace.kind == self.api_name()
+    }
 }
 
-async fn get_gitlab_version(
-    client: &reqwest::Client,
-    config: &GitLabConfig,
-) -> Result<GitLabVersion> {
-    let url = format!("{}/api/v4/version", config.url);
-    let response = client
-        .get(&url)
-        .header("PRIVATE-TOKEN", &config.token)
-        .send()
-        .await?
-        .error_for_status()?;
+/// `projects` without those whose namespace is not of `kind`, if given.
+fn of_namespace_kind(mut projects: Vec<Project>, kind: Option<NamespaceKind>) -> Vec<Project> {
+    if let Some(kind) = kind {
+        projects.retain(|project| kind.owns(project));
+    }
+    projects
+}
 
-    let version: GitLabVersion = response.json().await?;
-    Ok(version)
+impl SortDirection {
+    fn api_name(self) -> &'static str {
+        match self {
+            SortDirection::Asc => "asc",
+            SortDirection::Desc => "desc",
+        }
+    }
 }
 
-async fn get_projects(
-    client: &reqwest::Client,
-    config: &GitLabConfig,
-    include_archived: bool,
-) -> Result<Vec<Project>> {
-    let mut all_projects = Vec::new();
-    let mut page = 1;
-    let per_page = 50;
+/// An instance given on the command line for one run, instead of saved in the config.
+#[derive(Args, Clone, Default)]
+struct AdHocInstanceArgs {
+    /// URL of a GitLab instance to use for this run only, without saving it to the config
+    #[arg(
+        long,
+        env = "GITLAB_URL",
+        requires = "token",
+        conflicts_with = "instance"
+    )]
+    url: Option<String>,
 
-    loop {
-        let url = format!("{}/api/v4/projects", config.url);
-        let request = client
-            .get(&url)
-            .header("PRIVATE-TOKEN", &config.token)
-            .query(&[
-                ("simple", "true"),
-                ("per_page", &per_page.to_string()),
-                ("page", &page.to_string()),
-                ("order_by", "id"),
-                ("membership", "true"),
-                ("archived", &include_archived.to_string()),
-            ]);
+    /// API token for --url; ignored without it
+    #[arg(long, env = "GITLAB_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+impl AdHocInstanceArgs {
+    /// Replaces the configured instances with the one given by `--url`/`--token`, returning
+    /// its name.
+    fn apply(&self, config: &mut AppConfig) -> Result<Option<String>> {
+        let (Some(url), Some(token)) = (&self.url, &self.token) else {
+            return Ok(None);
+        };
+        let parsed =
+            reqwest::Url::parse(url).with_context(|| format!("Invalid GitLab URL '{}'", url))?;
+        let name = parsed.host_str().unwrap_or(url).to_string();
+        config.gitlab_instances = vec![GitLabConfig {
+            name: name.clone(),
+            url: url.clone(),
+            token: token.clone(),
+            ..Default::default()
+        }];
+        Ok(Some(name))
+    }
+}
+
+/// Listing order options shared by the commands that enumerate projects.
+#[derive(Args, Clone, Default)]
+struct ProjectOrderArgs {
+    /// Field GitLab orders the project listing by
+    #[arg(long, value_enum)]
+    order_by: Option<ProjectOrder>,
 
-        let response = request.send().await?.error_for_status()?;
-        let projects: Vec<Project> = response.json().await?;
+    /// Direction of --order-by
+    #[arg(long, value_enum)]
+    sort: Option<SortDirection>,
+}
+
+impl ProjectOrderArgs {
+    fn apply(&self, options: &mut ProjectListOptions) {
+        options.order_by = self.order_by.map(|order| order.api_name().to_string());
+        options.sort = self.sort.map(|sort| sort.api_name().to_string());
+    }
+}
 
-        if projects.is_empty() {
-            break;
+#[derive(Clone, Copy, ValueEnum)]
+enum SearchType {
+    /// GitLab's built-in search
+    Basic,
+    /// Elasticsearch-backed advanced search
+    Advanced,
+}
+
+impl SearchType {
+    fn api_name(self) -> &'static str {
+        match self {
+            SearchType::Basic => "basic",
+            SearchType::Advanced => "advanced",
         }
+    }
+}
 
-        all_projects.extend(projects);
-        page += 1;
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+enum SearchScope {
+    /// Code in the repository's default branch
+    Blobs,
+    /// Merge request titles and descriptions
+    #[value(name = "merge_requests")]
+    MergeRequests,
+    /// Issue titles and descriptions
+    Issues,
+    /// Pages of the project's wiki
+    #[value(name = "wiki_blobs")]
+    WikiBlobs,
+}
+
+impl SearchScope {
+    /// The scope name used by the GitLab search API.
+    fn api_name(self) -> &'static str {
+        match self {
+            SearchScope::Blobs => "blobs",
+            SearchScope::MergeRequests => "merge_requests",
+            SearchScope::Issues => "issues",
+            SearchScope::WikiBlobs => "wiki_blobs",
+        }
     }
+}
+
+// Parsed once per run, so the size difference between variants doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Commands {
+    /// Configure GitLab instances
+    Config(ConfigArgs),
+    /// Search for code in GitLab projects
+    Search(SearchArgs),
+    /// List projects in GitLab instance
+    Projects(ProjectsArgs),
+    /// Check the config file for problems
+    Validate,
+    /// Check that instances respond and how fast
+    Ping(PingArgs),
+    /// Keep a local index of an instance's projects, used by `search --all-projects`
+    Index(IndexArgs),
+    /// Diagnose the setup: config file, proxy settings, and each instance's reachability,
+    /// token and search capabilities
+    Doctor(DoctorArgs),
+    /// Run a small search at several concurrency levels and recommend a --concurrency
+    Bench(BenchArgs),
+}
 
-    Ok(all_projects)
+#[derive(Args, Clone)]
+struct DoctorArgs {
+    /// GitLab instance name (from config) [default: every configured instance]
+    #[arg(short, long)]
+    instance: Option<String>,
 }
 
-async fn search_project_blobs(
-    client: &reqwest::Client,
-    config: &GitLabConfig,
-    project_id: u64,
-    query: &str,
-) -> Result<Vec<SearchResultRaw>> {
-    let url = format!("{}/api/v4/projects/{}/search", config.url, project_id);
-    let response = client
-        .get(&url)
-        .header("PRIVATE-TOKEN", &config.token)
-        .query(&[("scope", "blobs"), ("search", query), ("per_page", "100")])
-        .send()
-        .await?
-        .error_for_status()?;
+#[derive(Args, Clone)]
+struct BenchArgs {
+    /// GitLab instance name (from config) [default: $GITLAB_INSTANCE, else the first one]
+    #[arg(short, long)]
+    instance: Option<String>,
 
-    let results: Vec<SearchResultRaw> = response.json().await?;
-    Ok(results)
+    /// Concurrency levels to try (comma-separated)
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 2, 5, 10, 20], value_parser = clap::value_parser!(u32).range(1..))]
+    levels: Vec<u32>,
+
+    /// Search this many of the most recently active projects at each level
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    projects: u32,
+}
+
+#[derive(Args, Clone)]
+struct IndexArgs {
+    /// GitLab instance name (from config), or `all` for every configured instance
+    #[arg(short, long)]
+    instance: Option<String>,
+
+    /// List every project and replace the index
+    #[arg(long, required_unless_present = "update", conflicts_with = "update")]
+    build: bool,
+
+    /// Add or refresh the projects active since the index was built or last updated
+    #[arg(long)]
+    update: bool,
+}
+
+#[derive(Args, Clone)]
+struct PingArgs {
+    /// GitLab instance name (from config), or `all` for every configured instance
+    #[arg(short, long)]
+    instance: Option<String>,
 }
 
-async fn handle_config_command(
+#[derive(Args, Clone)]
+struct ConfigArgs {
+    /// GitLab instance name
+    #[arg(short, long)]
     name: Option<String>,
+
+    /// GitLab URL
+    #[arg(short, long)]
     url: Option<String>,
+
+    /// GitLab API token
+    #[arg(short, long)]
     token: Option<String>,
+
+    /// File to read the GitLab API token from on each request, instead of storing it in the config
+    #[arg(long, conflicts_with = "token")]
+    token_file: Option<PathBuf>,
+
+    /// Group (ID or path) to list projects from when the token cannot list projects globally
+    #[arg(long)]
+    default_group: Option<String>,
+
+    /// List all configured GitLab instances
+    #[arg(short, long)]
     list: bool,
-) -> Result<()> {
-    let mut config = get_config().await?;
 
-    if list {
-        println!("Configured GitLab instances:");
-        if config.gitlab_instances.is_empty() {
-            println!("  No instances configured");
-        } else {
-            for instance in &config.gitlab_instances {
-                println!("  {} - {}", instance.name.green(), instance.url);
-            }
-        }
-        return Ok(());
-    }
+    /// Print the JSON Schema of the config file, e.g. for editor validation
+    #[arg(long)]
+    schema: bool,
 
-    let has_name = name.is_some();
-    let has_url = url.is_some();
-    let has_token = token.is_some();
+    /// Save the number of projects searched at the same time when --concurrency is omitted
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    set_concurrency: Option<u32>,
 
-    if let (Some(name_val), Some(url_val), Some(token_val)) = (name, url, token) {
-        if let Some(pos) = config
-            .gitlab_instances
-            .iter()
-            .position(|i| i.name == name_val)
-        {
-            config.gitlab_instances[pos] = GitLabConfig {
-                name: name_val.clone(),
-                url: url_val.clone(),
-                token: token_val.clone(),
-            };
-            println!("Updated GitLab instance: {}", name_val.green());
-        } else {
-            config.gitlab_instances.push(GitLabConfig {
-                name: name_val.clone(),
-                url: url_val.clone(),
-                token: token_val.clone(),
-            });
-            println!("Added new GitLab instance: {}", name_val.green());
-        }
+    /// Save the request timeout in seconds used when --timeout is omitted
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    set_timeout: Option<u64>,
 
-        save_config(&config).await?;
+    /// Save the number of retries used when --retries is omitted
+    #[arg(long)]
+    set_retries: Option<u32>,
 
-        let client = reqwest::Client::new();
-        let instance = config
-            .gitlab_instances
-            .iter()
-            .find(|i| i.name == name_val)
-            .unwrap();
+    /// Overwrite an existing instance of the same name without asking, even if its URL differs
+    #[arg(long)]
+    force: bool,
 
-        match get_gitlab_version(&client, instance).await {
-            Ok(version) => {
-                println!(
-                    "Successfully connected to GitLab instance: {} (version: {})",
-                    name_val.green(),
-                    version.version.cyan()
-                );
-            }
-            Err(e) => {
-                println!(
-                    "Failed to connect to GitLab instance: {} - Error: {}",
-                    name_val.red(),
-                    e
-                );
-            }
-        }
-    } else if has_name || has_url || has_token {
-        println!(
-            "{}",
-            "To configure a GitLab instance, you must provide name, url, and token".red()
-        );
-    } else {
-        println!(
-            "{}",
-            "Use --list to see configured instances or provide --name, --url, and --token to add/update an instance"
-                .yellow()
-        );
-    }
+    /// Never search this project (full path or ID) on the instance given with --name;
+    /// repeat for several
+    #[arg(long, value_name = "PROJECT", requires = "name")]
+    exclude_project: Vec<String>,
 
-    Ok(())
+    /// Remove a project from the instance's excluded projects; repeat for several
+    #[arg(long, value_name = "PROJECT", requires = "name")]
+    include_project: Vec<String>,
 }
 
-async fn handle_projects_command(instance: Option<String>, archived: bool) -> Result<()> {
-    let config = get_config().await?;
+#[derive(Args, Clone)]
+struct ProjectsArgs {
+    /// GitLab instance name (from config) [default: $GITLAB_INSTANCE, else the first one]
+    #[arg(short, long)]
+    instance: Option<String>,
 
-    let instance_config = if let Some(instance_name) = instance {
-        config
-            .gitlab_instances
-            .iter()
-            .find(|i| i.name == instance_name)
-            .with_context(|| format!("GitLab instance '{}' not found in config", instance_name))?
-    } else if !config.gitlab_instances.is_empty() {
-        &config.gitlab_instances[0]
-    } else {
-        return Err(anyhow::anyhow!(
-            "No GitLab instances configured. Use 'config' command to add one."
-        ));
-    };
+    #[command(flatten)]
+    ad_hoc: AdHocInstanceArgs,
 
-    println!(
-        "Fetching projects from GitLab instance: {}",
-        instance_config.name.green()
-    );
+    /// List only archived projects
+    #[arg(short, long)]
+    archived: bool,
 
-    let client = reqwest::Client::new();
-    let projects = get_projects(&client, instance_config, archived).await?;
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
 
-    println!("Found {} projects:", projects.len());
-    for project in projects {
-        println!(
-            "  {} (ID: {}) - {}",
-            project.name_with_namespace.green(),
-            project.id.to_string().cyan(),
-            project.web_url
-        );
-    }
+    /// List the projects of this group (ID or full path) and its subgroups
+    #[arg(short, long)]
+    group: Option<String>,
 
-    Ok(())
-}
+    /// With --group (or the instance's default group), leave out the projects of subgroups
+    #[arg(long)]
+    no_subgroups: bool,
 
-async fn handle_search_command(
-    query: String,
-    instance: Option<String>,
-    project: Option<String>,
-    all_projects: bool,
-) -> Result<()> {
-    let config = get_config().await?;
+    /// Only list projects in user (personal) or group namespaces
+    #[arg(long, value_enum)]
+    namespace_kind: Option<NamespaceKind>,
 
-    let instance_config = if let Some(instance_name) = instance {
-        config
-            .gitlab_instances
-            .iter()
-            .find(|i| i.name == instance_name)
-            .with_context(|| format!("GitLab instance '{}' not found in config", instance_name))?
-    } else if !config.gitlab_instances.is_empty() {
-        &config.gitlab_instances[0]
-    } else {
-        return Err(anyhow::anyhow!(
-            "No GitLab instances configured. Use 'config' command to add one."
-        ));
-    };
+    #[command(flatten)]
+    order: ProjectOrderArgs,
 
-    println!(
-        "Searching in GitLab instance: {}",
-        instance_config.name.green()
-    );
+    /// Show at most this many projects
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    limit: Option<u32>,
 
-    let client = reqwest::Client::new();
-
-    let projects_to_search = if let Some(project_id_or_path) = project {
-        if let Ok(project_id) = project_id_or_path.parse::<u64>() {
-            vec![Project {
-                id: project_id,
-                description: None,
-                name: project_id_or_path.clone(),
-                name_with_namespace: project_id_or_path.clone(),
-                path: project_id_or_path.clone(),
-                path_with_namespace: project_id_or_path,
-                created_at: String::new(),
-                web_url: String::new(),
-                last_activity_at: String::new(),
-                namespace: Namespace {
-                    id: 0,
-                    name: String::new(),
-                    path: String::new(),
-                    kind: String::new(),
-                    full_path: String::new(),
-                    parent_id: None,
-                    web_url: String::new(),
-                },
-            }]
-        } else {
-            let all_projects = get_projects(&client, instance_config, false).await?;
-            all_projects
-                .into_iter()
-                .filter(|p| p.path_with_namespace == project_id_or_path)
-                .collect()
-        }
-    } else if all_projects {
-        println!("Fetching all projects...");
-        get_projects(&client, instance_config, false).await?
-    } else {
-        return Err(anyhow::anyhow!(
-            "You must specify a project with --project or use --all-projects to search in all projects"
-        ));
-    };
+    /// With --limit, show this page of the listing (starting at 1)
+    #[arg(long, requires = "limit", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    page: u32,
 
-    if projects_to_search.is_empty() {
-        return Err(anyhow::anyhow!("No projects found to search in"));
-    }
+    /// Only print these fields, tab-separated one project per line (or as JSON objects
+    /// with just these keys)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    columns: Vec<ProjectColumn>,
+}
 
-    println!("Searching for: {}", query.cyan());
-    println!("Searching in {} projects...", projects_to_search.len());
+/// `Default` leaves every flag off and empty; unlike the command line, it includes no scope.
+#[derive(Args, Clone, Default)]
+struct SearchArgs {
+    /// Search query, or `-` to read it from stdin
+    #[arg(short, long, required_unless_present_any = ["commit", "query_file"])]
+    query: Option<String>,
 
-    let pb = ProgressBar::new(projects_to_search.len() as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+    /// Read the search query from a file, e.g. for long or multi-line queries
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["query", "commit"])]
+    query_file: Option<PathBuf>,
 
-    let results = Arc::new(Mutex::new(Vec::new()));
-    let tasks = projects_to_search.iter().map(|project| {
-        let client = client.clone();
-        let config = instance_config.clone();
-        let query = query.clone();
-        let project_id = project.id;
-        let project_name = project.name_with_namespace.clone();
-        let results = Arc::clone(&results);
-        let pb = pb.clone();
+    /// Instead of searching, report which projects contain this commit (SHA, branch or tag)
+    #[arg(long, conflicts_with_all = ["query", "totals", "template", "mr_changes", "lang", "ext", "show_empty"])]
+    commit: Option<String>,
 
-        async move {
-            match search_project_blobs(&client, &config, project_id, &query).await {
-                Ok(project_results) => {
-                    let mut results_guard = results.lock().await;
-                    for result in project_results {
-                        results_guard.push((project_name.clone(), result));
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error searching in project {}: {}", project_name, e);
-                }
-            }
-            pb.inc(1);
-            pb.set_message(format!("Searching in {}", project_name));
-        }
-    });
+    /// GitLab instance name (from config); repeat to search several instances, or use
+    /// `all` for every configured one [default: $GITLAB_INSTANCE, else the first one]
+    #[arg(short, long)]
+    instance: Vec<String>,
 
-    join_all(tasks).await;
-    pb.finish_with_message("Search completed");
+    #[command(flatten)]
+    ad_hoc: AdHocInstanceArgs,
 
-    let search_results = results.lock().await;
-    println!("\nFound {} results:", search_results.len());
+    /// Search several instances at the same time; `false` searches them one after another
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    parallel_instances: bool,
 
-    for (project_name, result) in search_results.iter() {
-        println!(
-            "\n{} - {}:{}",
-            project_name.green(),
-            result.path.cyan(),
-            result.startline.to_string().yellow()
-        );
+    /// Project ID or path with namespace; repeat to search several projects
+    #[arg(short, long)]
+    project: Vec<String>,
 
-        let lines = result.data.lines();
-        for (i, line) in lines.enumerate() {
-            println!(
-                "{}: {}",
-                (result.startline + i as u64).to_string().yellow(),
-                line
-            );
-        }
-    }
+    /// Read projects to search (ID or path with namespace, one per line) from this file
+    #[arg(long)]
+    input_file: Option<PathBuf>,
 
-    Ok(())
-}
+    /// Search in all projects (may be slow). Uses the project index when one was built
+    #[arg(short, long)]
+    all_projects: bool,
 
-#[tokio::main]
-async fn main() -> Result<()> {
+    /// List the projects from GitLab even when a project index exists
+    #[arg(long)]
+    no_index: bool,
+
+    /// Search the repository in the current directory, using its `origin` remote to pick
+    /// the instance and project
+    #[arg(long, conflicts_with_all = ["project", "input_file", "all_projects", "group"])]
+    from_git: bool,
+
+    /// Maximum number of projects looked up or searched at the same time on each instance
+    /// [default: the instance's `concurrency` in the config, else config
+    /// `default_concurrency`, else 10]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: Option<u32>,
+
+    /// Seconds a project may take to search, retries included, before it is reported as
+    /// failed and the search moves on. Unlike --timeout this bounds the whole project
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    per_project_timeout: Option<u64>,
+
+    /// Retry code searches for up to this many seconds while GitLab is still indexing:
+    /// when it answers 202 Accepted, or finds nothing in a project changed in the last
+    /// 15 minutes. Useful right after pushing
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    wait_index: Option<u64>,
+
+    /// Order in which projects are searched
+    #[arg(long, value_enum)]
+    sort_projects: Option<ProjectSort>,
+
+    /// Only search the first N projects (in --sort-projects order), e.g. for a quick sample
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_projects: Option<usize>,
+
+    #[command(flatten)]
+    order: ProjectOrderArgs,
+
+    /// Format each result with a template, e.g. "{project}:{path}:{line}".
+    /// Placeholders: {instance}, {project}, {path}, {line}, {ref}, {url}, {data}
+    #[arg(long)]
+    template: Option<String>,
+
+    /// Print one grep-style line per match, `instance/project:path:line: snippet`, e.g. for
+    /// editor quickfix lists. Status messages are left out
+    #[arg(long, conflicts_with_all = ["template", "preview_head", "context", "blame", "totals", "commit"])]
+    compact: bool,
+
+    /// Report the line and byte column of the first match in each code or wiki result:
+    /// `path:line:column:` with --compact, `line` and `column` fields with --format json
+    #[arg(long)]
+    column: bool,
+
+    /// Record searched projects in this file and skip them when re-run with the same file
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// What to search for; a comma-separated list searches several scopes in one run
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "blobs")]
+    scope: Vec<SearchScope>,
+
+    /// With --scope merge_requests, only keep merge requests whose changes contain the query
+    #[arg(long)]
+    mr_changes: bool,
+
+    /// With --scope issues or merge_requests, only find items with this label; repeat
+    /// (or separate with commas) to require several
+    #[arg(long, value_delimiter = ',')]
+    label: Vec<String>,
+
+    /// With --scope issues or merge_requests, only find items assigned to this username
+    #[arg(long, value_name = "USERNAME")]
+    assignee: Option<String>,
+
+    /// Extra query parameter for code search requests, as key=value (repeatable), for
+    /// GitLab search options without a dedicated flag
+    #[arg(long = "param", value_name = "KEY=VALUE", value_parser = parse_search_param)]
+    params: Vec<(String, String)>,
+
+    /// Only report the number of matches per project, using GitLab's X-Total header
+    #[arg(long)]
+    totals: bool,
+
+    /// Ignore cached results of this search and search again, refreshing the cache
+    #[arg(long)]
+    refresh: bool,
+
+    /// Neither read nor write the short-lived search result cache
+    #[arg(long, conflicts_with = "refresh")]
+    no_cache: bool,
+
+    /// Also list the searched projects without any match (with --totals, print their zero counts)
+    #[arg(long)]
+    show_empty: bool,
+
+    /// Print to stderr what will be searched: the instance, scopes, query as sent, extra
+    /// parameters, filters, whether advanced search is active and the projects
+    #[arg(long)]
+    explain: bool,
+
+    /// Like --explain, but stop before searching
+    #[arg(long)]
+    dry_run: bool,
+
+    /// After the results, print how many matches each namespace (group) has, most first
+    #[arg(long, conflicts_with_all = ["commit", "totals"])]
+    by_namespace: bool,
+
+    /// Skip projects that are forks of another project
+    #[arg(long, conflicts_with = "only_forks")]
+    no_forks: bool,
+
+    /// Only search projects that are forks of another project
+    #[arg(long)]
+    only_forks: bool,
+
+    /// Only search projects in user (personal) or group namespaces, e.g. `group` to skip
+    /// personal sandboxes
+    #[arg(long, value_enum)]
+    namespace_kind: Option<NamespaceKind>,
+
+    /// Only search projects where you have at least this role, e.g. `developer` for repos you can push to
+    #[arg(long, value_enum)]
+    min_access: Option<AccessRole>,
+
+    /// Ask for confirmation before searching more than this many projects on an instance
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    confirm_above: usize,
+
+    /// Search without asking for confirmation, however many projects are selected
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Also search archived projects; their results are tagged "[archived]"
+    #[arg(long)]
+    include_archived: bool,
+
+    /// Only search projects with activity since the previous --since-last-run run of
+    /// this search on the instance; the first run searches everything
+    #[arg(long, conflicts_with = "commit")]
+    since_last_run: bool,
+
+    /// Print only the results, without status messages or the progress bar (long-only, as
+    /// `-q` is --query)
+    #[arg(long)]
+    quiet: bool,
+
+    /// Sort results by project, path and line and disable colors and progress output, so
+    /// identical matches produce byte-identical output (e.g. to compare against a baseline hash)
+    #[arg(long)]
+    sorted_stable: bool,
+
+    /// Only print code results missing from this earlier `--format json` output, i.e. new matches
+    #[arg(long, conflicts_with_all = ["commit", "totals"])]
+    diff_baseline: Option<PathBuf>,
+
+    /// With --diff-baseline, also list baseline results that no longer match
+    #[arg(long, requires = "diff_baseline", conflicts_with = "output_dir")]
+    show_removed: bool,
+
+    /// Write each project's results to `<dir>/<project path>.txt` (`.json` with --format json)
+    /// instead of printing them
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Also write the query, instances, result counts per searched project and duration to
+    /// this JSON file
+    #[arg(long, conflicts_with_all = ["commit", "totals"])]
+    summary_json: Option<PathBuf>,
+
+    /// Print each result chunk as GitLab returns it, instead of merging overlapping or
+    /// adjacent chunks of the same file into one snippet
+    #[arg(long)]
+    no_merge_chunks: bool,
+
+    /// When all results come from a single project, print just `path:line` headers
+    /// without repeating the project on each
+    #[arg(long)]
+    relative_paths: bool,
+
+    /// Print matched snippets verbatim instead of escaping terminal control characters
+    #[arg(long)]
+    raw: bool,
+
+    /// Print the first N lines of each matched file under its match header
+    #[arg(long, value_name = "N", conflicts_with = "template")]
+    preview_head: Option<usize>,
+
+    /// Show the author and commit that last changed the first line of each match
+    #[arg(long, conflicts_with = "template")]
+    blame: bool,
+
+    /// Print N lines of the file before and after each match, fetched from the repository
+    #[arg(short = 'C', long, value_name = "N", conflicts_with = "template")]
+    context: Option<u64>,
+
+    /// Drop matched lines shorter than this many characters
+    #[arg(long)]
+    min_line_len: Option<usize>,
+
+    /// Drop matched lines longer than this many characters (e.g. minified files)
+    #[arg(long)]
+    max_line_len: Option<usize>,
+
+    /// Search all projects of this group (ID or full path) and its subgroups
+    #[arg(short, long)]
+    group: Option<String>,
+
+    /// With --group (or the instance's default group), leave out the projects of subgroups
+    #[arg(long)]
+    no_subgroups: bool,
+
+    /// Also search the projects listed in the instance's `excluded_projects`
+    #[arg(long)]
+    no_exclusions: bool,
+
+    /// Search implementation to use on instances with advanced search: `basic` matches
+    /// the query as typed, while `advanced` (Elasticsearch) splits it into tokens and
+    /// supports its query syntax, but can miss exact phrases. Defaults to the instance's choice
+    #[arg(long, value_enum)]
+    search_type: Option<SearchType>,
+
+    /// Only match files in this language (e.g. rust, python). Uses GitLab's `lang:` filter
+    /// with advanced search, and filters by file extension otherwise
+    #[arg(long)]
+    lang: Option<String>,
+
+    /// Only match files with these extensions (comma-separated or repeated, e.g. rs,toml).
+    /// A single extension is filtered by GitLab itself with advanced search
+    #[arg(long, value_delimiter = ',')]
+    ext: Vec<String>,
+
+    /// Only match files whose path matches this regular expression, e.g. 'src/.*\.rs$'.
+    /// With advanced search its leading directory is also passed to GitLab as `path:`
+    #[arg(long, value_name = "REGEX", value_parser = regex::Regex::new, conflicts_with_all = ["totals", "commit"])]
+    path_regex: Option<regex::Regex>,
+
+    /// Keep only the first match in each file, for "does this file contain X" scans
+    #[arg(long, conflicts_with_all = ["totals", "commit"])]
+    first_match_only: bool,
+
+    /// Only search files under this directory, by listing them and searching their contents
+    /// directly instead of using GitLab's search (for instances without advanced search)
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["totals", "commit"])]
+    tree_path: Option<String>,
+
+    /// Stop collecting results once this many are gathered across all projects, skipping
+    /// the projects not yet searched
+    #[arg(long, value_name = "N", conflicts_with_all = ["totals", "commit"])]
+    max_results: Option<usize>,
+
+    /// Only keep code matches whose snippet contains the query exactly, undoing GitLab's
+    /// splitting of queries like `foo.bar` into separate terms
+    #[arg(long, conflicts_with_all = ["totals", "commit"])]
+    literal: bool,
+
+    /// Only search projects in this namespace (full path) or one of its sub-namespaces
+    #[arg(long)]
+    namespace: Option<String>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Include the matched snippet (`data`) in JSON output. Snippets usually make up most
+    /// of the output size; `--include-data false` keeps only locations (path, line, ref)
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    include_data: bool,
+}
+
+/// The contents of `config.json`.
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+struct AppConfig {
+    /// Instances that can be searched; the first is used when --instance is omitted
+    gitlab_instances: Vec<GitLabConfig>,
+
+    /// User-Agent header sent with every API request, unless overridden with --user-agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+
+    /// Concurrency used when --concurrency is omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_concurrency: Option<u32>,
+
+    /// Request timeout in seconds used when --timeout is omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_timeout: Option<u64>,
+
+    /// Retries used when --retries is omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_retries: Option<u32>,
+}
+
+/// A single search hit, shaped by the scope it was found in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum SearchHit {
+    Blob(SearchResultRaw),
+    MergeRequest {
+        merge_request: MergeRequestResult,
+        /// Files whose changes contain the query, when `--mr-changes` is used
+        matching_files: Vec<String>,
+    },
+    Issue(IssueResult),
+    WikiBlob(WikiBlobResult),
+}
+
+impl SearchHit {
+    /// Orders hits within a project for `--sorted-stable`.
+    fn sort_key(&self) -> (&'static str, &str, u64, &str) {
+        match self {
+            SearchHit::Blob(result) => ("blobs", &result.path, result.startline, &result.data),
+            SearchHit::MergeRequest { merge_request, .. } => {
+                ("merge_requests", "", merge_request.iid, "")
+            }
+            SearchHit::Issue(issue) => ("issues", "", issue.iid, ""),
+            SearchHit::WikiBlob(result) => {
+                ("wiki_blobs", &result.path, result.startline, &result.data)
+            }
+        }
+    }
+
+    fn scope(&self) -> SearchScope {
+        match self {
+            SearchHit::Blob(_) => SearchScope::Blobs,
+            SearchHit::MergeRequest { .. } => SearchScope::MergeRequests,
+            SearchHit::Issue(_) => SearchScope::Issues,
+            SearchHit::WikiBlob(_) => SearchScope::WikiBlobs,
+        }
+    }
+}
+
+/// A search hit as printed by `--format json`.
+#[derive(Serialize)]
+#[serde(tag = "scope", rename_all = "snake_case")]
+enum JsonHit<'a> {
+    Blobs {
+        project: &'a str,
+        project_id: u64,
+        path: &'a str,
+        #[serde(rename = "ref")]
+        ref_field: &'a str,
+        startline: u64,
+        url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<&'a str>,
+    },
+    MergeRequests {
+        project: &'a str,
+        project_id: u64,
+        #[serde(flatten)]
+        merge_request: &'a MergeRequestResult,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        matching_files: &'a [String],
+    },
+    Issues {
+        project: &'a str,
+        project_id: u64,
+        #[serde(flatten)]
+        issue: &'a IssueResult,
+    },
+    WikiBlobs {
+        project: &'a str,
+        project_id: u64,
+        path: &'a str,
+        startline: u64,
+        url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<&'a str>,
+    },
+}
+
+impl<'a> JsonHit<'a> {
+    fn new(project: &'a Project, hit: &'a SearchHit, include_data: bool) -> Self {
+        match hit {
+            SearchHit::Blob(result) => JsonHit::Blobs {
+                project: &project.path_with_namespace,
+                project_id: project.id,
+                path: &result.path,
+                ref_field: &result.ref_field,
+                startline: result.startline,
+                url: blob_url(project, result),
+                data: include_data.then_some(result.data.as_str()),
+            },
+            SearchHit::MergeRequest {
+                merge_request,
+                matching_files,
+            } => JsonHit::MergeRequests {
+                project: &project.path_with_namespace,
+                project_id: project.id,
+                merge_request,
+                matching_files,
+            },
+            SearchHit::Issue(issue) => JsonHit::Issues {
+                project: &project.path_with_namespace,
+                project_id: project.id,
+                issue,
+            },
+            SearchHit::WikiBlob(result) => JsonHit::WikiBlobs {
+                project: &project.path_with_namespace,
+                project_id: project.id,
+                path: &result.path,
+                startline: result.startline,
+                url: wiki_url(project, result),
+                data: include_data.then_some(result.data.as_str()),
+            },
+        }
+    }
+}
+
+/// Whether a project contains the commit, as printed by `--commit --format json`.
+#[derive(Serialize)]
+struct JsonCommitPresence<'a> {
+    project: &'a str,
+    project_id: u64,
+    present: bool,
+}
+
+/// A per-project match count as printed by `--totals --format json`.
+#[derive(Serialize)]
+struct JsonTotal<'a> {
+    project: &'a str,
+    project_id: u64,
+    scope: &'static str,
+    total: Option<u64>,
+}
+
+/// Metrics about a search, written by `--summary-json`.
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    query: &'a str,
+    instances: Vec<&'a str>,
+    total_results: usize,
+    projects: Vec<JsonProjectCount<'a>>,
+    duration_secs: f64,
+}
+
+/// The number of results found in one project.
+#[derive(Serialize)]
+struct JsonProjectCount<'a> {
+    instance: &'a str,
+    project: &'a str,
+    project_id: u64,
+    results: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TemplateField {
+    Instance,
+    Project,
+    Path,
+    Line,
+    Ref,
+    Url,
+    Data,
+}
+
+#[derive(Debug)]
+enum TemplatePart {
+    Literal(String),
+    Field(TemplateField),
+}
+
+/// How matched snippets are printed.
+struct RenderOptions {
+    raw: bool,
+    min_line_len: Option<usize>,
+    max_line_len: Option<usize>,
+}
+
+impl RenderOptions {
+    /// Whether a snippet line passes the `--min-line-len`/`--max-line-len` filters.
+    fn keeps_line(&self, line: &str) -> bool {
+        let len = line.chars().count();
+        self.min_line_len.is_none_or(|min| len >= min)
+            && self.max_line_len.is_none_or(|max| len <= max)
+    }
+
+    fn clean<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.raw {
+            Cow::Borrowed(text)
+        } else {
+            sanitize(text)
+        }
+    }
+}
+
+/// Number of recent project searches used to estimate the remaining time.
+const ETA_WINDOW: usize = 20;
+
+/// Estimates the remaining search time from the most recently completed projects,
+/// which tracks rate limiting and slow stretches better than the overall average.
+#[derive(Default)]
+struct RollingEta {
+    latencies: VecDeque<Duration>,
+    completions: VecDeque<Instant>,
+}
+
+impl RollingEta {
+    fn record(&mut self, latency: Duration) {
+        if self.latencies.len() == ETA_WINDOW {
+            self.latencies.pop_front();
+            self.completions.pop_front();
+        }
+        self.latencies.push_back(latency);
+        self.completions.push_back(Instant::now());
+    }
+
+    fn average_latency(&self) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        Some(self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32)
+    }
+
+    /// Remaining time at the recent completion rate; `None` until two projects finished.
+    fn remaining(&self, remaining_projects: u64) -> Option<Duration> {
+        let (first, last) = (self.completions.front()?, self.completions.back()?);
+        let span = last.duration_since(*first);
+        let completed = self.completions.len() as u32 - 1;
+        if completed == 0 || span.is_zero() {
+            return None;
+        }
+        Some(span / completed * remaining_projects as u32)
+    }
+
+    fn message(&self, remaining_projects: u64) -> String {
+        let mut parts = Vec::new();
+        if let Some(remaining) = self.remaining(remaining_projects) {
+            parts.push(format!("~{} remaining", format_duration(remaining)));
+        }
+        if let Some(latency) = self.average_latency() {
+            parts.push(format!("{}ms/project", latency.as_millis()));
+        }
+        parts.join(", ")
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Progress of a resumable search, persisted with `--state-file`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SearchState {
+    instance: String,
+    query: String,
+    searched_project_ids: HashSet<u64>,
+}
+
+/// The base directory for the config and caches, and whether a missing config file may be
+/// created there. Containers without a home directory fall back to an existing
+/// `.gitlab-search-cli` in the current directory, and finally the temp directory, where the
+/// config stays in memory unless one is put there.
+fn config_base() -> (PathBuf, bool) {
+    // On Linux `dirs` already honours `$XDG_CONFIG_HOME`; elsewhere it needs a home directory.
+    let xdg_config_home = if cfg!(target_os = "linux") {
+        None
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+    };
+    config_base_from(
+        dirs::config_dir(),
+        xdg_config_home,
+        std::env::current_dir().ok(),
+    )
+}
+
+/// [`config_base`] given the platform config directory, `$XDG_CONFIG_HOME` and the current
+/// directory.
+fn config_base_from(
+    config_dir: Option<PathBuf>,
+    xdg_config_home: Option<PathBuf>,
+    current_dir: Option<PathBuf>,
+) -> (PathBuf, bool) {
+    if let Some(dir) = config_dir {
+        return (dir.join("gitlab-search-cli"), true);
+    }
+    if let Some(dir) = xdg_config_home.filter(|dir| dir.is_absolute()) {
+        return (dir.join("gitlab-search-cli"), true);
+    }
+    if let Some(dir) = current_dir
+        .map(|dir| dir.join(".gitlab-search-cli"))
+        .filter(|dir| dir.is_dir())
+    {
+        return (dir, true);
+    }
+    (std::env::temp_dir().join("gitlab-search-cli"), false)
+}
+
+/// The directory holding the config file and caches, one subdirectory per `--profile`.
+fn config_dir(global: &GlobalArgs) -> Result<PathBuf> {
+    let (base, _) = config_base();
+
+    match &global.profile {
+        Some(profile) => {
+            if profile.is_empty() || profile.starts_with('.') || profile.contains(['/', '\\']) {
+                return Err(anyhow::anyhow!("Invalid profile name '{}'", profile));
+            }
+            Ok(base.join(profile))
+        }
+        None => Ok(base),
+    }
+}
+
+async fn get_config(global: &GlobalArgs) -> Result<AppConfig> {
+    let config_dir = config_dir(global)?;
+    let config_path = config_dir.join("config.json");
+
+    if !config_path.exists() {
+        if global.no_config_autocreate {
+            return Err(anyhow::anyhow!(
+                "No config file at {} (not created because of --no-config-autocreate)",
+                config_path.display()
+            ));
+        }
+        let default_config = AppConfig::default();
+        if !config_base().1 {
+            return Ok(default_config);
+        }
+        let config_json = serde_json::to_string_pretty(&default_config)?;
+        std::fs::create_dir_all(&config_dir)
+            .and_then(|_| std::fs::write(&config_path, config_json))
+            .with_context(|| {
+                format!(
+                    "Failed to create config file {} (pass --no-config-autocreate to not create one)",
+                    config_path.display()
+                )
+            })?;
+    }
+
+    let config = Config::builder()
+        .add_source(File::from(config_path))
+        .build()?;
+
+    let app_config: AppConfig = config.try_deserialize()?;
+    Ok(app_config)
+}
+
+/// Checks a parsed config file, returning one message per problem found.
+fn validate_config(value: &serde_json::Value) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let instances = match value.get("gitlab_instances") {
+        Some(serde_json::Value::Array(instances)) => instances,
+        Some(_) => return vec!["'gitlab_instances' must be a list".to_string()],
+        None => return vec!["missing 'gitlab_instances' list".to_string()],
+    };
+
+    let mut names = HashSet::new();
+    for (index, instance) in instances.iter().enumerate() {
+        let field = |key: &str| instance.get(key).and_then(|v| v.as_str());
+        let label = match field("name") {
+            Some(name) if !name.trim().is_empty() => format!("instance #{} '{}'", index + 1, name),
+            _ => format!("instance #{}", index + 1),
+        };
+
+        if !instance.is_object() {
+            problems.push(format!("{}: must be an object", label));
+            continue;
+        }
+
+        match field("name") {
+            None => problems.push(format!("{}: missing 'name'", label)),
+            Some(name) if name.trim().is_empty() => {
+                problems.push(format!("{}: empty 'name'", label))
+            }
+            Some(name) => {
+                if !names.insert(name) {
+                    problems.push(format!("{}: duplicate name", label));
+                }
+            }
+        }
+
+        match field("url") {
+            None => problems.push(format!("{}: missing 'url'", label)),
+            Some(url) if url.trim().is_empty() => problems.push(format!("{}: empty 'url'", label)),
+            Some(url) => match reqwest::Url::parse(url) {
+                Ok(parsed) if !matches!(parsed.scheme(), "http" | "https") => {
+                    problems.push(format!(
+                        "{}: url '{}' must start with http:// or https://",
+                        label, url
+                    ))
+                }
+                Ok(parsed) if parsed.host_str().is_none() => {
+                    problems.push(format!("{}: url '{}' has no host", label, url))
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(format!("{}: malformed url '{}' ({})", label, url, e)),
+            },
+        }
+
+        let tokens = match instance.get("tokens") {
+            None => 0,
+            Some(serde_json::Value::Array(tokens)) => {
+                if tokens
+                    .iter()
+                    .any(|token| token.as_str().is_none_or(|token| token.trim().is_empty()))
+                {
+                    problems.push(format!(
+                        "{}: 'tokens' must only contain non-empty strings",
+                        label
+                    ));
+                }
+                tokens.len()
+            }
+            Some(_) => {
+                problems.push(format!("{}: 'tokens' must be a list of tokens", label));
+                0
+            }
+        };
+
+        match (field("token"), field("token_file")) {
+            (_, Some(path)) if !Path::new(path).is_file() => {
+                problems.push(format!("{}: token file '{}' does not exist", label, path))
+            }
+            (_, Some(_)) => {}
+            (None, None) if tokens == 0 => problems.push(format!(
+                "{}: missing 'token', 'token_file' or 'tokens'",
+                label
+            )),
+            (None, None) => {}
+            (Some(token), None) if token.trim().is_empty() => {
+                problems.push(format!("{}: empty 'token'", label))
+            }
+            (Some(_), None) => {}
+        }
+
+        match instance.get("extra_headers") {
+            None => {}
+            Some(serde_json::Value::Object(headers)) => {
+                for (name, value) in headers {
+                    match value.as_str() {
+                        Some(value) => {
+                            if let Err(e) = parse_header(name, value) {
+                                problems.push(format!("{}: {:#}", label, e));
+                            }
+                        }
+                        None => problems.push(format!(
+                            "{}: extra header '{}' must be a string",
+                            label, name
+                        )),
+                    }
+                }
+            }
+            Some(_) => problems.push(format!(
+                "{}: 'extra_headers' must be an object of header names to values",
+                label
+            )),
+        }
+
+        if let Some(base) = field("api_base") {
+            if !base.starts_with('/') {
+                problems.push(format!(
+                    "{}: 'api_base' '{}' must start with '/'",
+                    label, base
+                ));
+            }
+        }
+
+        if instance.get("concurrency").and_then(|v| v.as_u64()) == Some(0) {
+            problems.push(format!("{}: 'concurrency' must be at least 1", label));
+        }
+    }
+
+    for key in ["default_concurrency", "default_timeout"] {
+        if value.get(key).and_then(|v| v.as_u64()) == Some(0) {
+            problems.push(format!("'{}' must be at least 1", key));
+        }
+    }
+
+    // Catch remaining type mismatches (e.g. a number where a string is expected).
+    if problems.is_empty() {
+        if let Err(e) = serde_json::from_value::<AppConfig>(value.clone()) {
+            problems.push(e.to_string());
+        }
+    }
+
+    problems
+}
+
+async fn handle_validate_command(global: &GlobalArgs) -> Result<()> {
+    let config_path = config_dir(global)?.join("config.json");
+    if !config_path.exists() {
+        println!(
+            "No config file at {}; it will be created on first use",
+            config_path.display()
+        );
+        return Ok(());
+    }
+
+    let config_json = std::fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let problems = match serde_json::from_str::<serde_json::Value>(&config_json) {
+        Ok(value) => validate_config(&value),
+        Err(e) => vec![format!("invalid JSON: {}", e)],
+    };
+
+    if problems.is_empty() {
+        println!("{} is valid", config_path.display().to_string().green());
+        return Ok(());
+    }
+
+    println!("{}:", config_path.display().to_string().red());
+    for problem in &problems {
+        println!("  - {}", problem);
+    }
+    Err(anyhow::anyhow!(
+        "Config file has {} problem{}",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" }
+    ))
+}
+
+fn project_index_path(global: &GlobalArgs) -> Result<PathBuf> {
+    Ok(config_dir(global)?.join("project_index.json"))
+}
+
+/// The projects of one instance, as of `updated_at`.
+#[derive(Serialize, Deserialize)]
+struct IndexedProjects {
+    /// ISO 8601 UTC time the listing that last built or updated the entry started
+    updated_at: String,
+    projects: Vec<Project>,
+}
+
+/// Local copies of instances' project listings, keyed by instance, written by `index`.
+#[derive(Default, Serialize, Deserialize)]
+struct ProjectIndex {
+    instances: HashMap<String, IndexedProjects>,
+}
+
+impl ProjectIndex {
+    /// Loads the index, starting over if it is missing or unreadable.
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write project index {}", path.display()))
+    }
+
+    fn key(instance: &GitLabConfig) -> String {
+        format!("{}|{}{}", instance.name, instance.url, instance.api_base())
+    }
+}
+
+/// The indexed projects of an instance, for searches of all its projects (or a namespace)
+/// unless `--no-index` is given.
+fn indexed_projects(options: &SearchOptions, instance: &GitLabConfig) -> Option<IndexedProjects> {
+    let args = options.args;
+    if !(args.all_projects || args.namespace.is_some()) || args.group.is_some() || args.no_index {
+        return None;
+    }
+    ProjectIndex::load(&project_index_path(options.global).ok()?)
+        .instances
+        .remove(&ProjectIndex::key(instance))
+}
+
+/// Builds or incrementally updates the project index of each selected instance.
+async fn handle_index_command(args: IndexArgs, global: &GlobalArgs) -> Result<()> {
+    let config = get_config(global).await?;
+    let instances = select_instances(&config, args.instance.as_slice(), None)?;
+    let index_path = project_index_path(global)?;
+    let mut index = ProjectIndex::load(&index_path);
+
+    let mut failed = 0;
+    for instance in &instances {
+        match index_instance(global, &config, instance, args.update, &mut index).await {
+            Ok(()) => {}
+            Err(e) if instances.len() == 1 => return Err(e),
+            // Keep indexing the other instances; what succeeded is still saved.
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}", format!("{}: {:#}", instance.name, e).red());
+            }
+        }
+    }
+    index.save(&index_path)?;
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} instances could not be indexed",
+            failed,
+            instances.len()
+        ));
+    }
+    Ok(())
+}
+
+async fn index_instance(
+    global: &GlobalArgs,
+    config: &AppConfig,
+    instance: &GitLabConfig,
+    update: bool,
+    index: &mut ProjectIndex,
+) -> Result<()> {
+    let client =
+        gitlab_client(global, config, instance)?.with_project_cache(project_cache_path(global)?);
+    let key = ProjectIndex::key(instance);
+    let started = format_utc(unix_now());
+    // Full representations with archived projects, so searches can filter them locally.
+    let mut options = ProjectListOptions {
+        include_archived: true,
+        full: true,
+        ..Default::default()
+    };
+
+    if update {
+        let entry = index.instances.get_mut(&key).with_context(|| {
+            format!(
+                "No project index for instance '{}' yet; create it with `index --build`",
+                instance.name
+            )
+        })?;
+        options.last_activity_after = Some(entry.updated_at.clone());
+        let changed = list_projects(&client, None, &options).await?;
+        let count = changed.len();
+        for project in changed {
+            match entry.projects.iter_mut().find(|p| p.id == project.id) {
+                Some(indexed) => *indexed = project,
+                None => entry.projects.push(project),
+            }
+        }
+        entry.updated_at = started;
+        println!(
+            "{}: updated {} projects; {} indexed",
+            instance.name.green(),
+            count,
+            entry.projects.len()
+        );
+    } else {
+        let projects = list_projects(&client, None, &options).await?;
+        println!(
+            "{}: indexed {} projects",
+            instance.name.green(),
+            projects.len()
+        );
+        index.instances.insert(
+            key,
+            IndexedProjects {
+                updated_at: started,
+                projects,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Times a version request to each selected instance.
+async fn handle_ping_command(args: PingArgs, global: &GlobalArgs) -> Result<()> {
+    let config = get_config(global).await?;
+    let instances = select_instances(&config, args.instance.as_slice(), None)?;
+
+    let pings = instances.iter().map(|instance| {
+        let config = &config;
+        async move {
+            let client = gitlab_client(global, config, instance)?;
+            let started = Instant::now();
+            let version = client.version().await?;
+            Ok::<_, anyhow::Error>((started.elapsed(), version))
+        }
+    });
+    let outcomes = join_all(pings).await;
+
+    let mut failed = 0;
+    for (instance, outcome) in instances.iter().zip(outcomes) {
+        match outcome {
+            Ok((latency, version)) => println!(
+                "{} ({}): {} ms, GitLab {}",
+                instance.name.green(),
+                instance.url,
+                latency.as_millis().to_string().yellow(),
+                version.version.cyan()
+            ),
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "{} ({}): {}",
+                    instance.name.red(),
+                    instance.url,
+                    format!("{:#}", e).red()
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} instances could not be reached",
+            failed,
+            instances.len()
+        ));
+    }
+    Ok(())
+}
+
+/// The search `bench` times; any term works since only the requests matter.
+const BENCH_QUERY: &str = "README";
+
+/// One concurrency level tried by `bench`.
+struct BenchRound {
+    concurrency: u32,
+    projects_per_sec: f64,
+    errors: usize,
+}
+
+async fn handle_bench_command(args: BenchArgs, global: &GlobalArgs) -> Result<()> {
+    let config = get_config(global).await?;
+    let instance = match &args.instance {
+        Some(name) => config
+            .gitlab_instances
+            .iter()
+            .find(|i| &i.name == name)
+            .with_context(|| format!("GitLab instance '{}' not found in config", name))?,
+        None => default_instance(&config)?,
+    };
+
+    let client =
+        gitlab_client(global, &config, instance)?.with_project_cache(project_cache_path(global)?);
+    let list_options = ProjectListOptions {
+        order_by: Some("last_activity_at".to_string()),
+        sort: Some("desc".to_string()),
+        ..Default::default()
+    };
+    let project_ids: Vec<String> = list_projects(&client, None, &list_options)
+        .await?
+        .iter()
+        .take(args.projects as usize)
+        .map(|project| project.id.to_string())
+        .collect();
+    if project_ids.is_empty() {
+        return Err(anyhow::anyhow!("No projects found to search in"));
+    }
+    println!(
+        "Searching {} projects of {} for '{}' at each concurrency level",
+        project_ids.len(),
+        instance.name.green(),
+        BENCH_QUERY
+    );
+    println!();
+    println!(
+        "{:>11}  {:>10}  {:>6}",
+        "Concurrency", "Projects/s", "Errors"
+    );
+
+    let mut rounds = Vec::new();
+    for &concurrency in &args.levels {
+        let search_args = SearchArgs {
+            query: Some(BENCH_QUERY.to_string()),
+            concurrency: Some(concurrency),
+            scope: vec![SearchScope::Blobs],
+            parallel_instances: true,
+            include_data: true,
+            no_cache: true,
+            quiet: true,
+            yes: true,
+            ..Default::default()
+        };
+        let options = SearchOptions {
+            args: &search_args,
+            global,
+            config: &config,
+            query: BENCH_QUERY.to_string(),
+            scopes: vec![SearchScope::Blobs],
+            quiet: true,
+            project_ids_or_paths: project_ids.clone(),
+            git_remote: None,
+            progress: MultiProgress::new(),
+            prompt_lock: Mutex::new(()),
+            collected: AtomicUsize::new(0),
+            truncated: AtomicBool::new(false),
+            cache_updates: Mutex::new(Vec::new()),
+            last_run_updates: Mutex::new(Vec::new()),
+        };
+        let started = Instant::now();
+        let outcome = search_instance(&options, instance).await;
+        let elapsed = started.elapsed().as_secs_f64();
+        let errors = match &outcome {
+            // A cancelled round says nothing about the level, and the user wants to stop.
+            Ok(Some(search)) if search.interrupted => break,
+            Ok(Some(search)) => search.errored.len(),
+            Ok(None) => 0,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Error at concurrency {}: {:#}", concurrency, e).red()
+                );
+                project_ids.len()
+            }
+        };
+        let round = BenchRound {
+            concurrency,
+            projects_per_sec: project_ids.len() as f64 / elapsed.max(f64::EPSILON),
+            errors,
+        };
+        let error_rate = format!(
+            "{:.0}%",
+            100.0 * round.errors as f64 / project_ids.len() as f64
+        );
+        println!(
+            "{:>11}  {:>10.1}  {:>6}",
+            round.concurrency,
+            round.projects_per_sec,
+            if round.errors == 0 {
+                error_rate.green()
+            } else {
+                error_rate.red()
+            }
+        );
+        rounds.push(round);
+    }
+
+    // Past the point of diminishing returns, fewer parallel requests are kinder to the
+    // instance, so take the lowest level within 10% of the fastest error-free one.
+    let best = rounds
+        .iter()
+        .filter(|round| round.errors == 0)
+        .map(|round| round.projects_per_sec)
+        .fold(None, |best: Option<f64>, rate| {
+            Some(best.map_or(rate, |b| b.max(rate)))
+        });
+    let Some(best) = best else {
+        return Err(anyhow::anyhow!(
+            "Every concurrency level had errors; check the instance with `doctor`"
+        ));
+    };
+    let recommended = rounds
+        .iter()
+        .filter(|round| round.errors == 0 && round.projects_per_sec >= best * 0.9)
+        .map(|round| round.concurrency)
+        .min()
+        .unwrap_or(DEFAULT_CONCURRENCY);
+    println!();
+    println!(
+        "Recommended: --concurrency {} (save it with `config --set-concurrency {}`)",
+        recommended.to_string().green(),
+        recommended
+    );
+    Ok(())
+}
+
+/// Environment variables that change how requests are made, reported by `doctor`.
+const NETWORK_ENV_VARS: &[&str] = &[
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+    "NO_PROXY",
+    "no_proxy",
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+    "GITLAB_INSTANCE",
+];
+
+/// Pass/fail lines printed by `doctor`, counting the failures.
+#[derive(Default)]
+struct Checklist {
+    failures: usize,
+}
+
+impl Checklist {
+    fn pass(&mut self, message: impl std::fmt::Display) {
+        println!("  {} {}", "[ok]".green(), message);
+    }
+
+    fn warn(&mut self, message: impl std::fmt::Display) {
+        println!("  {} {}", "[warn]".yellow(), message);
+    }
+
+    fn fail(&mut self, message: impl std::fmt::Display) {
+        self.failures += 1;
+        println!("  {} {}", "[fail]".red(), message);
+    }
+
+    fn info(&mut self, message: impl std::fmt::Display) {
+        println!("  {} {}", "[info]".dimmed(), message);
+    }
+}
+
+async fn handle_doctor_command(args: DoctorArgs, global: &GlobalArgs) -> Result<()> {
+    let mut checks = Checklist::default();
+
+    println!("Config");
+    let config_path = config_dir(global)?.join("config.json");
+    match std::fs::read_to_string(&config_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => checks.warn(format!(
+            "no config file at {}; add an instance with 'config'",
+            config_path.display()
+        )),
+        Err(e) => checks.fail(format!("cannot read {}: {}", config_path.display(), e)),
+        Ok(json) => {
+            let problems = match serde_json::from_str::<serde_json::Value>(&json) {
+                Ok(value) => validate_config(&value),
+                Err(e) => vec![format!("invalid JSON: {}", e)],
+            };
+            if problems.is_empty() {
+                checks.pass(format!("{} is valid", config_path.display()));
+            }
+            for problem in problems {
+                checks.fail(format!("{}: {}", config_path.display(), problem));
+            }
+        }
+    }
+
+    println!("Environment");
+    let mut any_set = false;
+    for name in NETWORK_ENV_VARS {
+        if let Ok(value) = std::env::var(name) {
+            any_set = true;
+            // Proxy URLs may carry credentials.
+            let value = match reqwest::Url::parse(&value) {
+                Ok(mut url) if url.password().is_some() => {
+                    url.set_password(Some("REDACTED")).ok();
+                    url.to_string()
+                }
+                _ => value,
+            };
+            checks.info(format!("{}={}", name, value));
+        }
+    }
+    if !any_set {
+        checks.info("no proxy or certificate variables set; connecting directly");
+    }
+
+    let config = match get_config(global).await {
+        Ok(config) => config,
+        Err(e) => {
+            checks.fail(format!("config cannot be loaded: {:#}", e));
+            return doctor_outcome(&checks);
+        }
+    };
+    let names = vec![args.instance.unwrap_or_else(|| "all".to_string())];
+    let instances = match select_instances(&config, &names, None) {
+        Ok(instances) => instances,
+        Err(e) => {
+            println!("Instances");
+            checks.fail(format!("{:#}", e));
+            return doctor_outcome(&checks);
+        }
+    };
+
+    for instance in instances {
+        println!("Instance {} ({})", instance.name.green(), instance.url);
+        let client = match gitlab_client(global, &config, instance) {
+            Ok(client) => client,
+            Err(e) => {
+                checks.fail(format!("{:#}", e));
+                continue;
+            }
+        };
+
+        let started = Instant::now();
+        match client.version().await {
+            Ok(version) => checks.pass(format!(
+                "reachable{}, GitLab {} ({} ms)",
+                if instance.url.starts_with("https://") {
+                    " over TLS"
+                } else {
+                    ""
+                },
+                version.version,
+                started.elapsed().as_millis()
+            )),
+            Err(e) => {
+                match e.downcast_ref::<reqwest::Error>() {
+                    Some(http) if http.status() == Some(reqwest::StatusCode::UNAUTHORIZED) => {
+                        checks.pass("reachable");
+                        checks.fail("token rejected (401); it may be expired or revoked");
+                    }
+                    Some(http) if http.is_connect() || http.is_timeout() => checks.fail(format!(
+                        "cannot connect; check the URL, DNS, proxy and TLS certificates: {:#}",
+                        e
+                    )),
+                    _ => checks.fail(format!("request failed: {:#}", e)),
+                }
+                continue;
+            }
+        }
+
+        match client.token_info().await {
+            Ok(token) if token.scopes.iter().any(|s| s == "read_api" || s == "api") => {
+                checks.pass(format!(
+                    "token '{}' is valid with scopes {}",
+                    token.name,
+                    token.scopes.join(", ")
+                ))
+            }
+            Ok(token) => checks.fail(format!(
+                "token '{}' has scopes {}; searching needs 'read_api' (or 'api')",
+                token.name,
+                token.scopes.join(", ")
+            )),
+            Err(_) => checks.warn(
+                "token accepted, but its scopes cannot be inspected (GitLab before 15.5, or not a personal access token)",
+            ),
+        }
+
+        match client.supports_advanced_search().await {
+            Ok(true) => checks.pass("advanced search is enabled"),
+            Ok(false) => checks.warn(
+                "advanced search is not enabled; code search may be limited and --lang/--ext filter after searching",
+            ),
+            Err(e) => checks.warn(format!("advanced search could not be checked: {:#}", e)),
+        }
+    }
+
+    doctor_outcome(&checks)
+}
+
+fn doctor_outcome(checks: &Checklist) -> Result<()> {
+    if checks.failures > 0 {
+        return Err(anyhow::anyhow!(
+            "{} check{} failed",
+            checks.failures,
+            if checks.failures == 1 { "" } else { "s" }
+        ));
+    }
+    println!("\n{}", "Everything looks good".green());
+    Ok(())
+}
+
+async fn save_config(global: &GlobalArgs, config: &AppConfig) -> Result<()> {
+    let config_dir = config_dir(global)?;
+    std::fs::create_dir_all(&config_dir)
+        .with_context(|| format!("Failed to create config directory {}", config_dir.display()))?;
+    let config_path = config_dir.join("config.json");
+    let config_json = serde_json::to_string_pretty(&config)?;
+    std::fs::write(&config_path, config_json)
+        .with_context(|| format!("Failed to write config {}", config_path.display()))?;
+    Ok(())
+}
+
+fn project_cache_path(global: &GlobalArgs) -> Result<PathBuf> {
+    Ok(config_dir(global)?.join("projects_cache.json"))
+}
+
+fn query_cache_path(global: &GlobalArgs) -> Result<PathBuf> {
+    Ok(config_dir(global)?.join("query_cache.json"))
+}
+
+fn last_runs_path(global: &GlobalArgs) -> Result<PathBuf> {
+    Ok(config_dir(global)?.join("last_runs.json"))
+}
+
+/// Start times of completed `--since-last-run` searches, keyed by instance, scopes and
+/// query, as ISO 8601 UTC timestamps comparable with GitLab's `last_activity_at`.
+#[derive(Default, Serialize, Deserialize)]
+struct LastRuns {
+    runs: HashMap<String, String>,
+}
+
+impl LastRuns {
+    /// Loads the recorded runs, starting over if the file is missing or unreadable.
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &serde_json::to_string_pretty(self)?)
+            .context("Failed to write last run times")
+    }
+
+    /// Adds `updates` to the file in one write, warning if that fails.
+    fn save_updates(path: &Path, updates: Vec<(String, String)>) {
+        if updates.is_empty() {
+            return;
+        }
+        let mut last_runs = Self::load(path);
+        last_runs.runs.extend(updates);
+        if let Err(e) = last_runs.save(path) {
+            eprintln!("Warning: {}", e);
+        }
+    }
+}
+
+/// Formats seconds since the Unix epoch as an ISO 8601 UTC timestamp, the way GitLab does.
+fn format_utc(secs: u64) -> String {
+    // Civil-from-days conversion, see https://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86_400) as i64 + 719_468;
+    let seconds_of_day = secs % 86_400;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Orders UTC timestamps with and without fractional seconds ("...:05Z", "...:05.123Z")
+/// chronologically, which comparing the strings directly does not.
+fn timestamp_order_key(timestamp: &str) -> (&str, &str) {
+    let (seconds, rest) = timestamp.split_at(timestamp.len().min(19));
+    let fraction = rest
+        .strip_prefix('.')
+        .map(|rest| rest.trim_end_matches(|c: char| !c.is_ascii_digit()))
+        .unwrap_or("");
+    (seconds, fraction)
+}
+
+/// Remembers when a `--since-last-run` search started, to be saved once every instance is done.
+async fn record_last_run(options: &SearchOptions<'_>, key: Option<String>, started: String) {
+    if let Some(key) = key {
+        options.last_run_updates.lock().await.push((key, started));
+    }
+}
+
+/// How long the results of a search are reused by identical searches.
+const QUERY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Deserialize)]
+struct CachedQuery {
+    /// Seconds since the Unix epoch
+    saved_at: u64,
+    results: Vec<(Project, SearchHit)>,
+}
+
+/// Recent search results, keyed by instance, scopes, query and searched projects.
+#[derive(Default, Serialize, Deserialize)]
+struct QueryCache {
+    entries: HashMap<String, CachedQuery>,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The `--log-file` of this run, if any.
+static LOG_FILE: OnceLock<std::sync::Mutex<std::fs::File>> = OnceLock::new();
+
+fn open_log_file(path: &Path) -> Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+    LOG_FILE.set(std::sync::Mutex::new(file)).ok();
+    Ok(())
+}
+
+/// Appends an `event` with `fields` to the log file. Logging never fails the command.
+fn log_event(event: &str, mut fields: serde_json::Value) {
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+    fields["time"] = format_utc(unix_now()).into();
+    fields["event"] = event.into();
+    if let Ok(mut file) = file.lock() {
+        use std::io::Write as _;
+        writeln!(file, "{}", fields).ok();
+    }
+}
+
+/// Forwards stdout to the terminal or the pager, joined before exiting.
+static PAGER: std::sync::Mutex<Option<std::thread::JoinHandle<()>>> = std::sync::Mutex::new(None);
+
+/// Sends the rest of stdout through `$PAGER`, or `less`, when it is a terminal and the
+/// output does not fit on the screen; shorter output is printed as is, whatever the pager.
+/// Like git, `less` is run with `LESS=FRX` unless set, so colors pass through.
+#[cfg(unix)]
+fn start_pager(global: &GlobalArgs) {
+    use std::io::Write as _;
+    use std::os::unix::io::FromRawFd;
+
+    if global.no_pager || !std::io::stdout().is_terminal() {
+        return;
+    }
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    if pager.trim().is_empty() || pager.trim() == "cat" {
+        return;
+    }
+    let height = terminal_height();
+    std::io::stdout().flush().ok();
+    let mut pipe = [0; 2];
+    // SAFETY: `pipe` has room for both descriptors, and the terminal is duplicated before
+    // stdout is pointed at the pipe's write end; each descriptor is owned by one `File`.
+    let (output, terminal) = unsafe {
+        if libc::pipe(pipe.as_mut_ptr()) != 0 {
+            return;
+        }
+        let terminal = libc::dup(libc::STDOUT_FILENO);
+        libc::dup2(pipe[1], libc::STDOUT_FILENO);
+        libc::close(pipe[1]);
+        // Quitting the pager early ends the command quietly, as with git, rather than
+        // failing on the next write.
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        (
+            std::fs::File::from_raw_fd(pipe[0]),
+            std::fs::File::from_raw_fd(terminal),
+        )
+    };
+    // Colors are decided by whether stdout is a terminal, which it has stopped being.
+    if colored::control::SHOULD_COLORIZE.should_colorize() {
+        colored::control::set_override(true);
+    }
+    let forward = std::thread::spawn(move || forward_output(output, terminal, &pager, height));
+    if let Ok(mut pager) = PAGER.lock() {
+        *pager = Some(forward);
+    }
+}
+
+#[cfg(not(unix))]
+fn start_pager(_global: &GlobalArgs) {}
+
+/// Rows of the terminal on stdout, from the terminal itself, else `$LINES`, else 24.
+#[cfg(unix)]
+fn terminal_height() -> usize {
+    // SAFETY: TIOCGWINSZ only writes a `winsize` into the struct it is given.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+        && size.ws_row > 0
+    {
+        return size.ws_row as usize;
+    }
+    std::env::var("LINES")
+        .ok()
+        .and_then(|lines| lines.parse().ok())
+        .unwrap_or(24)
+}
+
+/// Holds back `output` until it has more lines than fit on the screen, then starts `pager`
+/// with what has been held back and the rest. Output that ends first goes to `terminal`.
+#[cfg(unix)]
+fn forward_output(
+    mut output: std::fs::File,
+    mut terminal: std::fs::File,
+    pager: &str,
+    height: usize,
+) {
+    use std::io::Write as _;
+
+    let mut held = Vec::new();
+    let mut chunk = [0; 8192];
+    // Leave a row for the prompt that follows.
+    while held.iter().filter(|&&b| b == b'\n').count() < height.saturating_sub(1) {
+        match output.read(&mut chunk) {
+            Ok(0) | Err(_) => {
+                terminal.write_all(&held).ok();
+                return;
+            }
+            Ok(read) => held.extend_from_slice(&chunk[..read]),
+        }
+    }
+
+    let mut command = std::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(pager)
+        .stdin(std::process::Stdio::piped())
+        .stdout(
+            terminal
+                .try_clone()
+                .map_or_else(|_| std::process::Stdio::inherit(), Into::into),
+        );
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let Ok(mut child) = command.spawn() else {
+        terminal.write_all(&held).ok();
+        std::io::copy(&mut output, &mut terminal).ok();
+        return;
+    };
+    if let Some(mut input) = child.stdin.take() {
+        // Once the pager is quit, dropping `output` makes further writes end the command.
+        if input.write_all(&held).is_ok() {
+            std::io::copy(&mut output, &mut input).ok();
+        }
+    }
+    drop(output);
+    child.wait().ok();
+}
+
+/// Ends stdout and waits for the output to be printed or the pager to be quit.
+fn finish_pager() {
+    let Some(forward) = PAGER.lock().ok().and_then(|mut pager| pager.take()) else {
+        return;
+    };
+    #[cfg(unix)]
+    {
+        use std::io::Write as _;
+        use std::os::unix::io::AsRawFd;
+
+        std::io::stdout().flush().ok();
+        if let Ok(null) = std::fs::OpenOptions::new().write(true).open("/dev/null") {
+            // SAFETY: both descriptors are open; this drops stdout's end of the pipe.
+            unsafe {
+                libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+            }
+        }
+    }
+    forward.join().ok();
+}
+
+/// Deprecated endpoints seen during this run, by endpoint, warned about once at the end.
+static DEPRECATIONS: std::sync::Mutex<BTreeMap<String, DeprecationNotice>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+fn record_deprecation(notice: &DeprecationNotice) {
+    if let Ok(mut deprecations) = DEPRECATIONS.lock() {
+        deprecations
+            .entry(notice.endpoint.clone())
+            .or_insert_with(|| notice.clone());
+    }
+}
+
+/// A single warning listing every deprecated endpoint used, if there were any.
+fn deprecation_warning() -> Option<String> {
+    let deprecations = DEPRECATIONS.lock().ok()?;
+    if deprecations.is_empty() {
+        return None;
+    }
+    let mut warning = String::from(
+        "Warning: GitLab flags API endpoints used by this run as deprecated; they may stop working after a GitLab upgrade:",
+    );
+    for notice in deprecations.values() {
+        // RFC 9745 sends `@<unix time>`; older drafts send `true` or an HTTP date.
+        let since = match notice.deprecation.strip_prefix('@') {
+            Some(secs) => secs.parse().ok().map(format_utc),
+            None if notice.deprecation.eq_ignore_ascii_case("true") => None,
+            None => Some(notice.deprecation.clone()),
+        };
+        let details: Vec<String> = since
+            .map(|since| format!("deprecated since {}", since))
+            .into_iter()
+            .chain(
+                notice
+                    .sunset
+                    .as_ref()
+                    .map(|sunset| format!("sunset {}", sunset)),
+            )
+            .collect();
+        write!(warning, "\n  {}", notice.endpoint).ok();
+        if !details.is_empty() {
+            write!(warning, " ({})", details.join(", ")).ok();
+        }
+    }
+    Some(warning)
+}
+
+fn log_request(instance: &str, record: &RequestRecord) {
+    log_event(
+        "request",
+        serde_json::json!({
+            "instance": instance,
+            "method": record.method,
+            "url": record.url,
+            "status": record.status,
+            "error": record.error,
+            "duration_ms": record.duration.as_millis() as u64,
+            "attempt": record.attempt,
+        }),
+    );
+}
+
+impl QueryCache {
+    /// Loads the cache without its expired entries, starting over if it is missing or unreadable.
+    fn load(path: &Path) -> Self {
+        let mut cache: Self = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        let now = unix_now();
+        cache
+            .entries
+            .retain(|_, entry| now.saturating_sub(entry.saved_at) < QUERY_CACHE_TTL.as_secs());
+        cache
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &serde_json::to_string(self)?)
+            .context("Failed to write search result cache")
+    }
+
+    /// Adds `updates` to the cache file in one write, so instances searched in parallel
+    /// don't overwrite each other's entries.
+    fn save_updates(path: &Path, updates: Vec<(String, CachedQuery)>) {
+        if updates.is_empty() {
+            return;
+        }
+        let mut cache = Self::load(path);
+        cache.entries.extend(updates);
+        if let Err(e) = cache.save(path) {
+            eprintln!("Warning: {}", e);
+        }
+    }
+}
+
+const RELEASES_URL: &str = "https://github.com/shkmv/gitlab-search-cli/releases";
+const LATEST_RELEASE_API_URL: &str =
+    "https://api.github.com/repos/shkmv/gitlab-search-cli/releases/latest";
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The latest release seen by the update check, and when it was looked up.
+#[derive(Serialize, Deserialize)]
+struct UpdateCheck {
+    /// Seconds since the Unix epoch
+    checked_at: u64,
+    latest: String,
+}
+
+/// Where the update check caches its result, or `None` if the check is disabled. It is
+/// skipped when stderr is not a terminal, so scripts never see the hint.
+fn update_check_path(global: &GlobalArgs) -> Option<PathBuf> {
+    if global.no_update_check
+        || std::env::var_os("GITLAB_SEARCH_CLI_NO_UPDATE_CHECK").is_some()
+        || !std::io::stderr().is_terminal()
+    {
+        return None;
+    }
+    Some(config_dir(global).ok()?.join("update_check.json"))
+}
+
+/// Parses `1.2.3` or `v1.2.3`, ignoring any pre-release suffix.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// A one-line upgrade hint if a newer release exists. The latest release is looked up at
+/// most once a day; failures are silent, as the check is only a convenience.
+async fn update_hint(cache_path: PathBuf) -> Option<String> {
+    let cached: Option<UpdateCheck> = std::fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok());
+    let latest = match cached {
+        Some(check)
+            if unix_now().saturating_sub(check.checked_at) < UPDATE_CHECK_INTERVAL.as_secs() =>
+        {
+            check.latest
+        }
+        _ => {
+            let check = UpdateCheck {
+                checked_at: unix_now(),
+                // A failed lookup also waits a day, reporting nothing newer meanwhile.
+                latest: fetch_latest_release()
+                    .await
+                    .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_string()),
+            };
+            if let Ok(json) = serde_json::to_string(&check) {
+                std::fs::write(&cache_path, json).ok();
+            }
+            check.latest
+        }
+    };
+
+    (parse_version(&latest)? > parse_version(env!("CARGO_PKG_VERSION"))?).then(|| {
+        format!(
+            "A newer version of gitlab-search-cli is available: {} -> {} ({})",
+            env!("CARGO_PKG_VERSION"),
+            latest.trim_start_matches('v'),
+            RELEASES_URL
+        )
+    })
+}
+
+async fn fetch_latest_release() -> Option<String> {
+    #[derive(Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+    let release: Release = reqwest::Client::builder()
+        .user_agent(DEFAULT_USER_AGENT)
+        .timeout(Duration::from_secs(5))
+        .build()
+        .ok()?
+        .get(LATEST_RELEASE_API_URL)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .json()
+        .await
+        .ok()?;
+    Some(release.tag_name)
+}
+
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Enough idle connections to serve a full fan-out burst without reconnecting.
+const DEFAULT_POOL_MAX_IDLE: usize = 32;
+const DEFAULT_TCP_KEEPALIVE_SECS: u64 = 60;
+const DEFAULT_CONCURRENCY: u32 = 10;
+/// Files of a `--tree-path` directory fetched at the same time, per project.
+const TREE_FETCH_CONCURRENCY: usize = 4;
+/// Shortest search term GitLab's search backends reliably accept.
+const MIN_SEARCH_TERM_LEN: usize = 3;
+
+fn parse_header_arg(arg: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = arg
+        .split_once(':')
+        .with_context(|| format!("expected \"Name: Value\", got '{}'", arg))?;
+    parse_header(name, value)
+}
+
+/// Parameters every search request sets itself, which `--param` must not override.
+const RESERVED_SEARCH_PARAMS: [&str; 3] = ["scope", "search", "per_page"];
+
+fn parse_search_param(arg: &str) -> Result<(String, String)> {
+    let (key, value) = arg
+        .split_once('=')
+        .with_context(|| format!("expected \"key=value\", got '{}'", arg))?;
+    if key.is_empty() {
+        return Err(anyhow::anyhow!("empty parameter name in '{}'", arg));
+    }
+    if RESERVED_SEARCH_PARAMS.contains(&key) {
+        return Err(anyhow::anyhow!(
+            "'{}' is set by the search itself and cannot be passed with --param",
+            key
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Builds the HTTP client shared by all requests of a command.
+fn build_client(global: &GlobalArgs, config: &AppConfig) -> Result<reqwest::Client> {
+    let user_agent = global
+        .user_agent
+        .as_deref()
+        .or(config.user_agent.as_deref())
+        .unwrap_or(DEFAULT_USER_AGENT);
+
+    // Searches send one or more requests per project to the same host, so connections
+    // are kept alive and reused instead of paying the TCP/TLS handshake each time.
+    let tcp_keepalive =
+        (global.tcp_keepalive > 0).then(|| Duration::from_secs(global.tcp_keepalive));
+
+    let default_headers: HeaderMap = global.headers.iter().cloned().collect();
+
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .default_headers(default_headers)
+        .pool_max_idle_per_host(global.pool_max_idle)
+        .tcp_keepalive(tcp_keepalive)
+        // Project listings and search results are large, repetitive JSON that shrinks
+        // several times over when compressed.
+        .gzip(!global.no_compression)
+        .brotli(!global.no_compression)
+        .deflate(!global.no_compression);
+    if let Some(timeout) = global.timeout.or(config.default_timeout) {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+    builder.build().context("Failed to build HTTP client")
+}
+
+/// Creates the API client for `instance` with the options shared by every command.
+fn gitlab_client(
+    global: &GlobalArgs,
+    config: &AppConfig,
+    instance: &GitLabConfig,
+) -> Result<GitLabClient> {
+    let mut client = GitLabClient::new(build_client(global, config)?, instance.clone())
+        .with_request_logging(global.print_requests)
+        .with_retries(global.retries.or(config.default_retries).unwrap_or(0))
+        .with_deprecation_observer(record_deprecation);
+    if LOG_FILE.get().is_some() {
+        let name = instance.name.clone();
+        client = client.with_request_observer(move |record| log_request(&name, record));
+    }
+    Ok(client)
+}
+
+fn load_search_state(path: &Path) -> Result<Option<SearchState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let state_json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read state file {}", path.display()))?;
+    let state = serde_json::from_str(&state_json)
+        .with_context(|| format!("Failed to parse state file {}", path.display()))?;
+    Ok(Some(state))
+}
+
+/// Replaces `path` with `contents` through a temporary file next to it, so an interrupted
+/// write never leaves a truncated file behind.
+fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let tmp_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path).inspect_err(|_| {
+        std::fs::remove_file(&tmp_path).ok();
+    })
+}
+
+/// Writes the state through a temporary file so an interruption never leaves it half-written.
+fn save_search_state(path: &Path, state: &SearchState) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_string(state)?)
+        .with_context(|| format!("Failed to write state file {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to write state file {}", path.display()))?;
+    Ok(())
+}
+
+/// Identifies a code result across runs: project path, file path, start line and snippet.
+type BaselineKey = (String, String, u64, String);
+
+/// A code result in a baseline saved with `--format json`.
+#[derive(Deserialize)]
+struct BaselineHit {
+    project: String,
+    path: String,
+    startline: u64,
+    data: Option<String>,
+}
+
+/// Loads the code results of a baseline, each with its key and its entry as saved.
+fn load_baseline(path: &Path) -> Result<Vec<(BaselineKey, serde_json::Value)>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read baseline {}", path.display()))?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&contents).with_context(|| {
+        format!(
+            "Baseline {} is not the output of search --format json",
+            path.display()
+        )
+    })?;
+
+    entries
+        .into_iter()
+        .filter(|entry| entry["scope"] == "blobs")
+        .map(|entry| {
+            let hit: BaselineHit = serde_json::from_value(entry.clone()).with_context(|| {
+                format!("Baseline {} has a malformed result", path.display())
+            })?;
+            let data = hit.data.with_context(|| {
+                format!(
+                    "Baseline {} was saved with --include-data false; results are matched by their snippets",
+                    path.display()
+                )
+            })?;
+            Ok(((hit.project, hit.path, hit.startline, data), entry))
+        })
+        .collect()
+}
+
+/// Searches the files under `dir` for lines containing `query`, ignoring case like GitLab's
+/// basic search. Each matching line becomes a result, as GitLab would return it.
+async fn search_tree(
+    client: &GitLabClient,
+    project_id: u64,
+    dir: &str,
+    query: &str,
+) -> Result<Vec<SearchResultRaw>> {
+    let files = client.tree_files(project_id, dir).await?;
+    let needle = query.to_lowercase();
+    // A few files at a time, so a large directory does not flood the instance.
+    let contents: Vec<Result<String>> = stream::iter(&files)
+        .map(|path| client.raw_file(project_id, path, "HEAD"))
+        .buffered(TREE_FETCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut results = Vec::new();
+    for (path, contents) in files.iter().zip(contents) {
+        let contents = contents.with_context(|| format!("Failed to fetch {}", path))?;
+        // Binary files have nothing to show.
+        if contents.contains('\0') {
+            continue;
+        }
+        let filename = path.rsplit('/').next().unwrap_or(path);
+        let basename = path
+            .rsplit_once('.')
+            .filter(|(_, extension)| !extension.contains('/'))
+            .map_or(path.as_str(), |(stem, _)| stem);
+        for (i, line) in contents.lines().enumerate() {
+            if line.to_lowercase().contains(&needle) {
+                results.push(SearchResultRaw {
+                    basename: basename.to_string(),
+                    data: format!("{}\n", line),
+                    path: path.clone(),
+                    filename: filename.to_string(),
+                    id: None,
+                    ref_field: "HEAD".to_string(),
+                    startline: i as u64 + 1,
+                    project_id,
+                });
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Whether GitLab attributes a code match to another project than the one it was found in.
+fn reported_elsewhere(project: &Project, hit: &SearchHit) -> bool {
+    matches!(hit, SearchHit::Blob(result) if result.project_id != project.id)
+}
+
+/// Attributes code matches to the project GitLab reports for them, which can differ from the
+/// project that was searched in cross-project searches. Each unknown project is looked up
+/// once; matches whose project cannot be looked up keep the searched project.
+async fn relabel_result_projects(
+    client: &GitLabClient,
+    searched: &[Project],
+    results: &mut [(Project, SearchHit)],
+) {
+    let mut projects: HashMap<u64, Project> = searched.iter().map(|p| (p.id, p.clone())).collect();
+    let unknown: BTreeSet<u64> = results
+        .iter()
+        .filter(|(project, hit)| reported_elsewhere(project, hit))
+        .filter_map(|(_, hit)| match hit {
+            SearchHit::Blob(result) => Some(result.project_id),
+            SearchHit::MergeRequest { .. } | SearchHit::Issue(_) | SearchHit::WikiBlob(_) => None,
+        })
+        .filter(|id| !projects.contains_key(id))
+        .collect();
+    let lookups = join_all(
+        unknown
+            .iter()
+            .map(|id| async move { (*id, client.project(&id.to_string()).await) }),
+    )
+    .await;
+    for (id, lookup) in lookups {
+        match lookup {
+            Ok(project) => {
+                projects.insert(id, project);
+            }
+            Err(e) => eprintln!("Warning: could not look up project {}: {}", id, e),
+        }
+    }
+
+    for (project, hit) in results.iter_mut() {
+        if let SearchHit::Blob(result) = hit {
+            if result.project_id != project.id {
+                if let Some(actual) = projects.get(&result.project_id) {
+                    *project = actual.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Searches one project in the given scope, returning its hits.
+async fn search_project(
+    client: &GitLabClient,
+    project_id: u64,
+    query: &str,
+    scope: SearchScope,
+    mr_changes: bool,
+    tree_path: Option<&str>,
+) -> Result<Vec<SearchHit>> {
+    match (scope, tree_path) {
+        (SearchScope::Blobs, Some(dir)) => Ok(search_tree(client, project_id, dir, query)
+            .await?
+            .into_iter()
+            .map(SearchHit::Blob)
+            .collect()),
+        (SearchScope::Blobs, None) => Ok(client
+            .search_blobs(project_id, query)
+            .await?
+            .into_iter()
+            .map(SearchHit::Blob)
+            .collect()),
+        (SearchScope::MergeRequests, _) => {
+            let merge_requests = client.search_merge_requests(project_id, query).await?;
+            let mut hits = Vec::new();
+            let needle = query.to_lowercase();
+            for merge_request in merge_requests {
+                let mut matching_files = Vec::new();
+                if mr_changes {
+                    let diffs = client
+                        .merge_request_diffs(project_id, merge_request.iid)
+                        .await?;
+                    matching_files = diffs
+                        .into_iter()
+                        .filter(|d| d.diff.to_lowercase().contains(&needle))
+                        .map(|d| d.new_path)
+                        .collect();
+                    if matching_files.is_empty() {
+                        continue;
+                    }
+                }
+                hits.push(SearchHit::MergeRequest {
+                    merge_request,
+                    matching_files,
+                });
+            }
+            Ok(hits)
+        }
+        (SearchScope::WikiBlobs, _) => Ok(client
+            .search_wiki_blobs(project_id, query)
+            .await?
+            .into_iter()
+            .map(SearchHit::WikiBlob)
+            .collect()),
+        (SearchScope::Issues, _) => Ok(client
+            .search_issues(project_id, query)
+            .await?
+            .into_iter()
+            .map(SearchHit::Issue)
+            .collect()),
+    }
+}
+
+/// Parses an output template, rejecting unknown placeholders. `{{` and `}}` produce literal braces.
+fn parse_template(template: &str) -> Result<Vec<TemplatePart>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => {
+                            return Err(anyhow::anyhow!(
+                                "Unclosed placeholder '{{{}' in template",
+                                name
+                            ))
+                        }
+                    }
+                }
+                let field = match name.as_str() {
+                    "instance" => TemplateField::Instance,
+                    "project" => TemplateField::Project,
+                    "path" => TemplateField::Path,
+                    "line" => TemplateField::Line,
+                    "ref" => TemplateField::Ref,
+                    "url" => TemplateField::Url,
+                    "data" => TemplateField::Data,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Unknown template placeholder '{{{}}}'. Available: {{instance}}, {{project}}, {{path}}, {{line}}, {{ref}}, {{url}}, {{data}}",
+                            name
+                        ))
+                    }
+                };
+                if !literal.is_empty() {
+                    parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(TemplatePart::Field(field));
+            }
+            '}' => return Err(anyhow::anyhow!("Unmatched '}}' in template")),
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        parts.push(TemplatePart::Literal(literal));
+    }
+
+    Ok(parts)
+}
+
+/// The project's full name as shown in result headers, tagged if the project is archived.
+fn project_heading(project: &Project) -> String {
+    if project.archived {
+        format!("{} [archived]", project.name_with_namespace)
+    } else {
+        project.name_with_namespace.clone()
+    }
+}
+
+fn blob_url(project: &Project, result: &SearchResultRaw) -> String {
+    format!(
+        "{}/-/blob/{}/{}#L{}",
+        project.web_url, result.ref_field, result.path, result.startline
+    )
+}
+
+/// The wiki page a wiki match is on.
+fn wiki_url(project: &Project, result: &WikiBlobResult) -> String {
+    format!("{}/-/wikis/{}", project.web_url, result.basename)
+}
+
+/// Escapes control characters (other than tabs and newlines) so that snippets from
+/// binary-ish files cannot garble the terminal.
+fn sanitize(text: &str) -> Cow<'_, str> {
+    if !text
+        .chars()
+        .any(|c| c.is_control() && c != '\t' && c != '\n')
+    {
+        return Cow::Borrowed(text);
+    }
+
+    let mut sanitized = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() && c != '\t' && c != '\n' {
+            sanitized.extend(c.escape_default());
+        } else {
+            sanitized.push(c);
+        }
+    }
+    Cow::Owned(sanitized)
+}
+
+fn render_template(
+    parts: &[TemplatePart],
+    instance: &str,
+    project: &Project,
+    result: &SearchResultRaw,
+    options: &RenderOptions,
+) -> String {
+    let mut output = String::new();
+    for part in parts {
+        match part {
+            TemplatePart::Literal(text) => output.push_str(text),
+            TemplatePart::Field(TemplateField::Instance) => output.push_str(instance),
+            TemplatePart::Field(TemplateField::Project) => {
+                output.push_str(&project.name_with_namespace)
+            }
+            TemplatePart::Field(TemplateField::Path) => output.push_str(&result.path),
+            TemplatePart::Field(TemplateField::Line) => {
+                output.push_str(&result.startline.to_string())
+            }
+            TemplatePart::Field(TemplateField::Ref) => output.push_str(&result.ref_field),
+            TemplatePart::Field(TemplateField::Url) => output.push_str(&blob_url(project, result)),
+            TemplatePart::Field(TemplateField::Data) => {
+                let data = result
+                    .data
+                    .lines()
+                    .filter(|line| options.keeps_line(line))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                output.push_str(&options.clean(data.trim_end()))
+            }
+        }
+    }
+    output
+}
+
+/// Whether `full_path` is `namespace` itself or nested below it. Matching whole path
+/// segments keeps `team` from also selecting `teamwork`.
+fn in_namespace(full_path: &str, namespace: &str) -> bool {
+    let namespace = namespace.trim_matches('/');
+    full_path == namespace
+        || full_path
+            .strip_prefix(namespace)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// File extensions used to filter by `--lang` when the instance lacks advanced search.
+/// Unknown languages are taken to be an extension themselves (e.g. `--lang rs`).
+fn lang_extensions(lang: &str) -> Vec<String> {
+    let extensions: &[&str] = match lang.to_lowercase().as_str() {
+        "rust" => &["rs"],
+        "python" => &["py", "pyi"],
+        "javascript" | "js" => &["js", "mjs", "cjs", "jsx"],
+        "typescript" | "ts" => &["ts", "tsx", "mts", "cts"],
+        "go" | "golang" => &["go"],
+        "java" => &["java"],
+        "kotlin" => &["kt", "kts"],
+        "scala" => &["scala", "sc"],
+        "ruby" => &["rb"],
+        "php" => &["php"],
+        "c" => &["c", "h"],
+        "c++" | "cpp" => &["cpp", "cc", "cxx", "hpp", "hh", "hxx", "h"],
+        "c#" | "csharp" => &["cs"],
+        "swift" => &["swift"],
+        "shell" | "bash" | "sh" => &["sh", "bash"],
+        "yaml" => &["yml", "yaml"],
+        "json" => &["json"],
+        "markdown" => &["md", "markdown"],
+        "html" => &["html", "htm"],
+        "css" => &["css", "scss", "sass"],
+        "sql" => &["sql"],
+        "terraform" | "hcl" => &["tf", "tfvars", "hcl"],
+        _ => return vec![lang.trim_start_matches('.').to_lowercase()],
+    };
+    extensions.iter().map(|ext| ext.to_string()).collect()
+}
+
+/// The directory a path regex's matches must contain, from its literal start up to the
+/// last `/` (e.g. `src/bin/` for `^src/bin/.*\.rs`), for GitLab's `path:` filter.
+fn regex_literal_dir(pattern: &str) -> Option<String> {
+    // With alternation the leading literal only covers one of the branches.
+    if pattern.contains('|') {
+        return None;
+    }
+    let literal: String = pattern
+        .trim_start_matches('^')
+        .chars()
+        .take_while(|c| !r".^$*+?()[]{}\".contains(*c))
+        .collect();
+    let dir = &literal[..literal.rfind('/')? + 1];
+    (dir != "/").then(|| dir.to_string())
+}
+
+fn has_extension(path: &str, extensions: &[String]) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+}
+
+/// The last line number covered by a blob result.
+fn chunk_end(result: &SearchResultRaw) -> u64 {
+    result.startline + result.data.lines().count().max(1) as u64 - 1
+}
+
+/// Merges blob results whose line ranges overlap or touch within the same file, so a
+/// cluster of nearby matches prints as one snippet instead of repeating shared lines.
+/// Each merged file keeps the position of its first result; other hits are untouched.
+fn merge_overlapping_chunks(results: Vec<(Project, SearchHit)>) -> Vec<(Project, SearchHit)> {
+    enum Slot {
+        Hit(Box<(Project, SearchHit)>),
+        File(usize),
+    }
+
+    let mut slots = Vec::new();
+    let mut files: Vec<(Project, Vec<SearchResultRaw>)> = Vec::new();
+    let mut file_index: HashMap<(u64, String, String), usize> = HashMap::new();
+    for (project, hit) in results {
+        match hit {
+            SearchHit::Blob(result) => {
+                let key = (project.id, result.path.clone(), result.ref_field.clone());
+                match file_index.get(&key) {
+                    Some(&index) => files[index].1.push(result),
+                    None => {
+                        file_index.insert(key, files.len());
+                        slots.push(Slot::File(files.len()));
+                        files.push((project, vec![result]));
+                    }
+                }
+            }
+            hit => slots.push(Slot::Hit(Box::new((project, hit)))),
+        }
+    }
+
+    let mut files: Vec<Option<(Project, Vec<SearchResultRaw>)>> = files
+        .into_iter()
+        .map(|(project, mut chunks)| {
+            chunks.sort_by_key(|chunk| chunk.startline);
+            let mut merged: Vec<SearchResultRaw> = Vec::with_capacity(chunks.len());
+            for chunk in chunks {
+                let Some(last) = merged
+                    .last_mut()
+                    .filter(|last| chunk.startline <= chunk_end(last) + 1)
+                else {
+                    merged.push(chunk);
+                    continue;
+                };
+                let overlap = (chunk_end(last) + 1 - chunk.startline) as usize;
+                let extra: Vec<&str> = chunk.data.lines().skip(overlap).collect();
+                if extra.is_empty() {
+                    continue;
+                }
+                if !last.data.is_empty() && !last.data.ends_with('\n') {
+                    last.data.push('\n');
+                }
+                last.data.push_str(&extra.join("\n"));
+                if chunk.data.ends_with('\n') {
+                    last.data.push('\n');
+                }
+            }
+            Some((project, merged))
+        })
+        .collect();
+
+    let mut merged = Vec::new();
+    for slot in slots {
+        match slot {
+            Slot::Hit(hit) => merged.push(*hit),
+            Slot::File(index) => {
+                if let Some((project, chunks)) = files[index].take() {
+                    merged.extend(
+                        chunks
+                            .into_iter()
+                            .map(|chunk| (project.clone(), SearchHit::Blob(chunk))),
+                    );
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// Keeps the earliest match of each file (and wiki page), dropping the later ones while
+/// leaving the order of what remains untouched.
+fn first_match_per_file(results: Vec<(Project, SearchHit)>) -> Vec<(Project, SearchHit)> {
+    fn file_key(project: &Project, hit: &SearchHit) -> Option<(u64, bool, String, u64)> {
+        match hit {
+            SearchHit::Blob(result) => {
+                Some((project.id, false, result.path.clone(), result.startline))
+            }
+            SearchHit::WikiBlob(result) => {
+                Some((project.id, true, result.path.clone(), result.startline))
+            }
+            SearchHit::MergeRequest { .. } | SearchHit::Issue(_) => None,
+        }
+    }
+
+    let mut first_lines: HashMap<(u64, bool, String), u64> = HashMap::new();
+    for (project, hit) in &results {
+        if let Some((id, wiki, path, startline)) = file_key(project, hit) {
+            let first = first_lines.entry((id, wiki, path)).or_insert(startline);
+            *first = (*first).min(startline);
+        }
+    }
+    let mut kept = HashSet::new();
+    results
+        .into_iter()
+        .filter(|(project, hit)| match file_key(project, hit) {
+            Some((id, wiki, path, startline)) => {
+                first_lines[&(id, wiki, path.clone())] == startline && kept.insert((id, wiki, path))
+            }
+            None => true,
+        })
+        .collect()
+}
+
+fn is_forbidden(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        == Some(reqwest::StatusCode::FORBIDDEN)
+}
+
+/// Explains a 403 from the token's scopes, when GitLab can tell which scopes it has.
+/// Returns an empty string on instances too old to describe the token.
+async fn token_scope_hint(client: &GitLabClient) -> String {
+    let Ok(token) = client.token_info().await else {
+        return String::new();
+    };
+    let scopes = if token.scopes.is_empty() {
+        "no scopes".to_string()
+    } else {
+        format!("scopes {}", token.scopes.join(", "))
+    };
+    if token
+        .scopes
+        .iter()
+        .any(|scope| scope == "read_api" || scope == "api")
+    {
+        format!(
+            ": token '{}' has the API scopes it needs, so its role or the instance's settings deny access",
+            token.name
+        )
+    } else {
+        format!(
+            ": token '{}' has {}; listing projects needs the 'read_api' (or 'api') scope",
+            token.name, scopes
+        )
+    }
+}
+
+/// Lists the projects of `group`, or the token's projects if no group is given. Tokens
+/// scoped to a single group (e.g. group access tokens) get a 403 from the global listing,
+/// in which case the instance's `default_group` is used instead when configured.
+async fn list_projects(
+    client: &GitLabClient,
+    group: Option<&str>,
+    options: &ProjectListOptions,
+) -> Result<Vec<Project>> {
+    if let Some(group) = group {
+        return match client.group_projects(group, options).await {
+            Err(e) if is_forbidden(&e) => {
+                let hint = token_scope_hint(client).await;
+                Err(e.context(format!(
+                    "GitLab refused to list the projects of group '{}' (403 Forbidden){}",
+                    group, hint
+                )))
+            }
+            result => result,
+        };
+    }
+
+    match client.projects(options).await {
+        Err(e) if is_forbidden(&e) => match &client.config().default_group {
+            Some(default_group) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Listing projects is forbidden for this token; using default group '{}'",
+                        default_group
+                    )
+                    .yellow()
+                );
+                client.group_projects(default_group, options).await
+            }
+            None => {
+                let hint = token_scope_hint(client).await;
+                Err(e.context(format!(
+                    "GitLab refused to list projects (403 Forbidden){}. If the token is scoped to a \
+                     group, pass --group <id> or configure the instance with --default-group",
+                    hint
+                )))
+            }
+        },
+        result => result,
+    }
+}
+
+/// Probes the instance once so a bad URL or token fails fast with a single error,
+/// instead of one error per project once the search has fanned out.
+async fn check_connectivity(client: &GitLabClient) -> Result<()> {
+    let instance = client.config();
+    client.version().await.map(|_| ()).map_err(|e| {
+        let hint = match e.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.status() == Some(reqwest::StatusCode::UNAUTHORIZED) => {
+                "the token was rejected; it may be expired or revoked"
+            }
+            Some(e) if e.is_connect() || e.is_timeout() => {
+                "the server could not be reached; check the URL, DNS and TLS setup"
+            }
+            Some(_) => "check the instance URL and token",
+            // Not an HTTP failure, e.g. an unreadable token file.
+            None => return e,
+        };
+        e.context(format!(
+            "Cannot connect to GitLab instance '{}' at {} ({})",
+            instance.name, instance.url, hint
+        ))
+    })
+}
+
+/// The host and project path of a git remote URL.
+#[derive(Debug)]
+struct GitRemote {
+    host: String,
+    path: String,
+}
+
+impl GitRemote {
+    /// Parses `https://host/group/project.git`, `ssh://git@host:2222/group/project.git`
+    /// and scp-like `git@host:group/project.git` remotes.
+    fn parse(url: &str) -> Option<Self> {
+        let (host, path) = if url.contains("://") {
+            let url = reqwest::Url::parse(url).ok()?;
+            (url.host_str()?.to_string(), url.path().to_string())
+        } else {
+            let (host, path) = url.split_once(':')?;
+            let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+            (host.to_string(), path.to_string())
+        };
+
+        let path = path.trim_matches('/');
+        let path = path.strip_suffix(".git").unwrap_or(path);
+        if host.is_empty() || path.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_lowercase(),
+            path: path.to_string(),
+        })
+    }
+
+    fn matches_instance(&self, instance: &GitLabConfig) -> bool {
+        reqwest::Url::parse(&instance.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .is_some_and(|host| host == self.host)
+    }
+
+    /// The project path on `instance`, dropping the instance's own path prefix for
+    /// instances served below the domain root (e.g. `https://example.com/gitlab`).
+    fn project_path(&self, instance: &GitLabConfig) -> String {
+        let prefix = reqwest::Url::parse(&instance.url)
+            .map(|url| url.path().trim_matches('/').to_string())
+            .unwrap_or_default();
+        if prefix.is_empty() {
+            return self.path.clone();
+        }
+        self.path
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .unwrap_or(&self.path)
+            .to_string()
+    }
+}
+
+/// Reads the `origin` remote of the git repository in the current directory.
+fn origin_remote() -> Result<GitRemote> {
+    let output = std::process::Command::new("git")
+        .args(["remote", "get-url", "origin"])
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Could not read the origin remote: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let url = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    GitRemote::parse(&url)
+        .with_context(|| format!("Could not parse the origin remote URL '{}'", url))
+}
+
+/// Reads a project list file: one ID or path per line, ignoring blank lines and `#` comments.
+fn read_project_list(path: &Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read project list {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Looks up the given projects concurrently, at most as many at a time as the semaphore
+/// allows, keeping their order and dropping duplicates.
+async fn resolve_projects(
+    client: &GitLabClient,
+    ids_or_paths: &[String],
+    semaphore: &Semaphore,
+) -> Result<Vec<Project>> {
+    let lookups = ids_or_paths.iter().map(|id_or_path| async move {
+        let _permit = semaphore.acquire().await?;
+        client
+            .project(id_or_path)
+            .await
+            .with_context(|| format!("Failed to look up project '{}'", id_or_path))
+    });
+
+    let mut seen = HashSet::new();
+    let mut projects = Vec::new();
+    for project in join_all(lookups).await {
+        let project = project?;
+        if seen.insert(project.id) {
+            projects.push(project);
+        }
+    }
+    Ok(projects)
+}
+
+async fn handle_config_command(args: ConfigArgs, global: &GlobalArgs) -> Result<()> {
+    let ConfigArgs {
+        name,
+        url,
+        token,
+        token_file,
+        default_group,
+        list,
+        schema,
+        set_concurrency,
+        set_timeout,
+        set_retries,
+        force,
+        exclude_project,
+        include_project,
+    } = args;
+    if schema {
+        let schema = schemars::schema_for!(AppConfig);
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+    let mut config = get_config(global).await?;
+
+    if list {
+        println!("Configured GitLab instances:");
+        if config.gitlab_instances.is_empty() {
+            println!("  No instances configured");
+        } else {
+            for instance in &config.gitlab_instances {
+                println!("  {} - {}", instance.name.green(), instance.url);
+                if !instance.excluded_projects.is_empty() {
+                    println!("    excluded: {}", instance.excluded_projects.join(", "));
+                }
+            }
+        }
+        if let Some(concurrency) = config.default_concurrency {
+            println!("Default concurrency: {}", concurrency);
+        }
+        if let Some(timeout) = config.default_timeout {
+            println!("Default timeout: {}s", timeout);
+        }
+        if let Some(retries) = config.default_retries {
+            println!("Default retries: {}", retries);
+        }
+        return Ok(());
+    }
+
+    let has_name = name.is_some();
+    let has_url = url.is_some();
+    let has_token = token.is_some() || token_file.is_some();
+
+    if set_concurrency.is_some() || set_timeout.is_some() || set_retries.is_some() {
+        if let Some(concurrency) = set_concurrency {
+            config.default_concurrency = Some(concurrency);
+            println!("Default concurrency set to {}", concurrency);
+        }
+        if let Some(timeout) = set_timeout {
+            config.default_timeout = Some(timeout);
+            println!("Default timeout set to {}s", timeout);
+        }
+        if let Some(retries) = set_retries {
+            config.default_retries = Some(retries);
+            println!("Default retries set to {}", retries);
+        }
+        save_config(global, &config).await?;
+        if !(has_name || has_url || has_token) {
+            return Ok(());
+        }
+    }
+    if !exclude_project.is_empty() || !include_project.is_empty() {
+        let name_val = name.as_deref().unwrap_or_default();
+        let instance = config
+            .gitlab_instances
+            .iter_mut()
+            .find(|i| i.name == name_val)
+            .with_context(|| format!("GitLab instance '{}' not found in config", name_val))?;
+        for project in exclude_project {
+            if !instance.excluded_projects.contains(&project) {
+                println!(
+                    "Excluding {} from searches on {}",
+                    project.cyan(),
+                    name_val.green()
+                );
+                instance.excluded_projects.push(project);
+            }
+        }
+        for project in include_project {
+            let excluded = instance.excluded_projects.len();
+            instance.excluded_projects.retain(|p| *p != project);
+            if instance.excluded_projects.len() < excluded {
+                println!(
+                    "Including {} in searches on {} again",
+                    project.cyan(),
+                    name_val.green()
+                );
+            } else {
+                println!(
+                    "{} was not excluded on {}",
+                    project.cyan(),
+                    name_val.green()
+                );
+            }
+        }
+        save_config(global, &config).await?;
+        if !(has_url || has_token) {
+            return Ok(());
+        }
+    }
+    let credentials = match (token, token_file) {
+        (Some(token), _) => Some((token, None)),
+        (None, Some(path)) => Some((
+            String::new(),
+            Some(std::path::absolute(&path).context("Invalid token file path")?),
+        )),
+        (None, None) => None,
+    };
+
+    if let (Some(name_val), Some(url_val), Some((token_val, token_file))) = (name, url, credentials)
+    {
+        if let Some(pos) = config
+            .gitlab_instances
+            .iter()
+            .position(|i| i.name == name_val)
+        {
+            let existing = &mut config.gitlab_instances[pos];
+            if !force && existing.url.trim_end_matches('/') != url_val.trim_end_matches('/') {
+                confirm_overwrite(&name_val, &existing.url, &url_val)?;
+            }
+            existing.url = url_val.clone();
+            existing.token = token_val.clone();
+            existing.token_file = token_file;
+            if default_group.is_some() {
+                existing.default_group = default_group;
+            }
+            println!("Updated GitLab instance: {}", name_val.green());
+        } else {
+            config.gitlab_instances.push(GitLabConfig {
+                name: name_val.clone(),
+                url: url_val.clone(),
+                token: token_val.clone(),
+                token_file,
+                default_group,
+                ..Default::default()
+            });
+            println!("Added new GitLab instance: {}", name_val.green());
+        }
+
+        save_config(global, &config).await?;
+
+        let instance = config
+            .gitlab_instances
+            .iter()
+            .find(|i| i.name == name_val)
+            .unwrap();
+        let client = gitlab_client(global, &config, instance)?;
+
+        match client.version().await {
+            Ok(version) => {
+                println!(
+                    "Successfully connected to GitLab instance: {} (version: {})",
+                    name_val.green(),
+                    version.version.cyan()
+                );
+            }
+            Err(e) => {
+                println!(
+                    "Failed to connect to GitLab instance: {} - Error: {}",
+                    name_val.red(),
+                    e
+                );
+            }
+        }
+    } else if has_name || has_url || has_token {
+        println!(
+            "{}",
+            "To configure a GitLab instance, you must provide name, url, and token (or token file)"
+                .red()
+        );
+    } else {
+        println!(
+            "{}",
+            "Use --list to see configured instances or provide --name, --url, and --token (or --token-file) to add/update an instance"
+                .yellow()
+        );
+    }
+
+    Ok(())
+}
+
+async fn handle_projects_command(args: ProjectsArgs, global: &GlobalArgs) -> Result<()> {
+    let ProjectsArgs {
+        instance,
+        ad_hoc,
+        archived,
+        format,
+        group,
+        no_subgroups,
+        namespace_kind,
+        order,
+        limit,
+        page,
+        columns,
+    } = args;
+    let mut config = get_config(global).await?;
+    let instance = ad_hoc.apply(&mut config)?.or(instance);
+
+    let instance_config = if let Some(instance_name) = instance {
+        config
+            .gitlab_instances
+            .iter()
+            .find(|i| i.name == instance_name)
+            .with_context(|| format!("GitLab instance '{}' not found in config", instance_name))?
+    } else {
+        default_instance(&config)?
+    };
+
+    let client = gitlab_client(global, &config, instance_config)?
+        .with_project_cache(project_cache_path(global)?);
+    let mut list_options = ProjectListOptions {
+        only_archived: archived,
+        // The simple representation has no `archived` flag to tag projects with.
+        full: archived,
+        exclude_subgroups: no_subgroups,
+        ..Default::default()
+    };
+    order.apply(&mut list_options);
+
+    // Slices the enumerated projects for display; the API is still paged through in full.
+    let display_range = |total: usize| match limit {
+        Some(limit) => {
+            let start = ((page - 1) as usize * limit as usize).min(total);
+            start..(start + limit as usize).min(total)
+        }
+        None => 0..total,
+    };
+
+    if format == OutputFormat::Markdown {
+        return Err(anyhow::anyhow!(
+            "--format markdown is only supported by the search command"
+        ));
+    }
+    start_pager(global);
+    if format == OutputFormat::Json {
+        let projects = of_namespace_kind(
+            list_projects(&client, group.as_deref(), &list_options).await?,
+            namespace_kind,
+        );
+        let range = display_range(projects.len());
+        if columns.is_empty() {
+            println!("{}", serde_json::to_string_pretty(&projects[range])?);
+        } else {
+            let rows: Vec<serde_json::Map<String, serde_json::Value>> = projects[range]
+                .iter()
+                .map(|project| {
+                    columns
+                        .iter()
+                        .map(|column| (column.name().to_string(), column.value(project)))
+                        .collect()
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        }
+        return Ok(());
+    }
+
+    // Selected columns are meant for scripts, so print nothing but the rows.
+    if !columns.is_empty() {
+        let projects = of_namespace_kind(
+            list_projects(&client, group.as_deref(), &list_options).await?,
+            namespace_kind,
+        );
+        let range = display_range(projects.len());
+        for project in &projects[range] {
+            let row: Vec<String> = columns
+                .iter()
+                .map(|column| match column.value(project) {
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string(),
+                })
+                .collect();
+            println!("{}", row.join("\t"));
+        }
+        return Ok(());
+    }
+
+    println!(
+        "Fetching projects from GitLab instance: {}",
+        instance_config.name.green()
+    );
+
+    let mut projects = of_namespace_kind(
+        list_projects(&client, group.as_deref(), &list_options).await?,
+        namespace_kind,
+    );
+    let total = projects.len();
+    let range = display_range(total);
+
+    if limit.is_some() && range.is_empty() {
+        println!("Found {} projects, none on page {}", total, page);
+    } else if limit.is_some() {
+        println!(
+            "Found {} projects, showing {}-{}:",
+            total,
+            range.start + 1,
+            range.end
+        );
+    } else {
+        println!("Found {} projects:", total);
+    }
+    for project in projects.drain(range) {
+        println!(
+            "  {} (ID: {}) - {}",
+            project_heading(&project).green(),
+            project.id.to_string().cyan(),
+            project.web_url
+        );
+    }
+
+    Ok(())
+}
+
+/// Options of a search that are the same for every instance searched.
+struct SearchOptions<'a> {
+    args: &'a SearchArgs,
+    global: &'a GlobalArgs,
+    config: &'a AppConfig,
+    query: String,
+    scopes: Vec<SearchScope>,
+    quiet: bool,
+    project_ids_or_paths: Vec<String>,
+    git_remote: Option<GitRemote>,
+    progress: MultiProgress,
+    /// Keeps instances searched in parallel from prompting at the same time
+    prompt_lock: Mutex<()>,
+    /// Results gathered so far by all instances, counted against `--max-results`
+    collected: AtomicUsize,
+    /// Set once `--max-results` is reached
+    truncated: AtomicBool,
+    /// Results of completed searches, saved to the query cache once every instance is done
+    cache_updates: Mutex<Vec<(String, CachedQuery)>>,
+    /// `--since-last-run` marks of completed searches, saved the same way
+    last_run_updates: Mutex<Vec<(String, String)>>,
+}
+
+/// What one instance contributed to a search.
+struct InstanceSearch {
+    client: GitLabClient,
+    /// Bounds the concurrent requests sent to this instance
+    semaphore: Arc<Semaphore>,
+    results: Vec<(Project, SearchHit)>,
+    totals: Vec<(Project, SearchScope, Option<u64>)>,
+    commit_presence: Vec<(Project, bool)>,
+    /// Projects searched without errors
+    completed: Vec<Project>,
+    /// Projects whose search failed
+    errored: Vec<Project>,
+    from_cache: bool,
+    interrupted: bool,
+}
+
+/// Picks the instances to search: those named with --instance (`all` for every configured
+/// one), else the one matching the git remote, else the first configured instance.
+fn select_instances<'a>(
+    config: &'a AppConfig,
+    names: &[String],
+    git_remote: Option<&GitRemote>,
+) -> Result<Vec<&'a GitLabConfig>> {
+    if config.gitlab_instances.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No GitLab instances configured. Use 'config' command to add one."
+        ));
+    }
+
+    if names.iter().any(|name| name == "all") {
+        return Ok(config.gitlab_instances.iter().collect());
+    }
+
+    if !names.is_empty() {
+        let mut instances: Vec<&GitLabConfig> = Vec::new();
+        for name in names {
+            let instance = config
+                .gitlab_instances
+                .iter()
+                .find(|i| &i.name == name)
+                .with_context(|| format!("GitLab instance '{}' not found in config", name))?;
+            if !instances.iter().any(|i| i.name == instance.name) {
+                instances.push(instance);
+            }
+        }
+        return Ok(instances);
+    }
+
+    if let Some(remote) = git_remote {
+        let instance = config
+            .gitlab_instances
+            .iter()
+            .find(|i| remote.matches_instance(i))
+            .with_context(|| {
+                format!(
+                    "No configured GitLab instance matches the origin remote host '{}'. Add one with: \
+                     gitlab-search-cli config --name <name> --url https://{} --token <token>",
+                    remote.host, remote.host
+                )
+            })?;
+        return Ok(vec![instance]);
+    }
+
+    Ok(vec![default_instance(config)?])
+}
+
+/// The instance used when none is given: the one named by `GITLAB_INSTANCE`, e.g. set per
+/// CI environment, else the first configured one.
+fn default_instance(config: &AppConfig) -> Result<&GitLabConfig> {
+    if let Some(name) = std::env::var("GITLAB_INSTANCE")
+        .ok()
+        .filter(|name| !name.trim().is_empty())
+    {
+        return config
+            .gitlab_instances
+            .iter()
+            .find(|i| i.name == name.trim())
+            .with_context(|| {
+                format!(
+                    "GitLab instance '{}' (from GITLAB_INSTANCE) not found in config",
+                    name.trim()
+                )
+            });
+    }
+    config
+        .gitlab_instances
+        .first()
+        .context("No GitLab instances configured. Use 'config' command to add one.")
+}
+
+/// Asks on the terminal whether to go ahead with searching `count` projects, failing if
+/// the answer is no or there is no terminal to ask on.
+fn confirm_search(progress: &MultiProgress, instance: &str, count: usize) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "Refusing to search {} projects on {} without confirmation; pass --yes to proceed",
+            count,
+            instance
+        ));
+    }
+
+    let answer = progress.suspend(|| {
+        eprint!(
+            "Search {} projects on {}? This may take a while. [y/N] ",
+            count, instance
+        );
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })?;
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Search cancelled"))
+    }
+}
+
+/// How recent a project's last activity must be for an empty code search to be taken as
+/// GitLab not having indexed the change yet.
+const RECENT_ACTIVITY_SECS: u64 = 15 * 60;
+
+fn recently_active(project: &Project) -> bool {
+    let since = format_utc(unix_now().saturating_sub(RECENT_ACTIVITY_SECS));
+    timestamp_order_key(&project.last_activity_at) > timestamp_order_key(&since)
+}
+
+/// Repeats `search` with backoff for up to `wait` while GitLab is still indexing the
+/// project, or, with `retry_empty`, while it finds nothing.
+async fn wait_for_index<F, Fut>(
+    wait: Duration,
+    retry_empty: bool,
+    search: F,
+) -> Result<Vec<SearchHit>>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<SearchHit>>>,
+{
+    let deadline = Instant::now() + wait;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let outcome = search().await;
+        let pending = match &outcome {
+            Ok(hits) => retry_empty && hits.is_empty(),
+            Err(e) => e.downcast_ref::<SearchIndexPending>().is_some(),
+        };
+        if !pending || Instant::now() + delay > deadline {
+            return outcome;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(30));
+    }
+}
+
+/// Runs `future`, failing it once `timeout` has passed.
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out after {}s", timeout.as_secs()))?,
+        None => future.await,
+    }
+}
+
+/// Asks before replacing an instance whose URL differs from the one being configured,
+/// since a reused name more likely points at a different server than a moved one.
+fn confirm_overwrite(name: &str, existing_url: &str, new_url: &str) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "Instance '{}' already points at {}; pass --force to replace it with {}",
+            name,
+            existing_url,
+            new_url
+        ));
+    }
+
+    eprint!(
+        "Instance '{}' already points at {}. Replace it with {}? [y/N] ",
+        name, existing_url, new_url
+    );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Instance '{}' left unchanged", name))
+    }
+}
+
+/// Enumerates and searches the projects of one instance. Returns `None` when the state
+/// file shows that every project was already searched.
+async fn search_instance(
+    options: &SearchOptions<'_>,
+    instance_config: &GitLabConfig,
+) -> Result<Option<InstanceSearch>> {
+    let args = options.args;
+    let query = &options.query;
+    let scopes = &options.scopes;
+    let quiet = options.quiet;
+    let (totals, mr_changes) = (args.totals, args.mr_changes);
+
+    if !quiet {
+        println!(
+            "Searching in GitLab instance: {}",
+            instance_config.name.green()
+        );
+    }
+
+    let client = gitlab_client(options.global, options.config, instance_config)?
+        .with_project_cache(project_cache_path(options.global)?)
+        .with_blob_search_params(args.params.clone())
+        .with_search_type(args.search_type.map(|t| t.api_name().to_string()))
+        .with_work_item_filter(WorkItemFilter {
+            labels: args.label.clone(),
+            assignee: args
+                .assignee
+                .as_deref()
+                .map(|user| user.trim_start_matches('@').to_string()),
+        });
+    check_connectivity(&client).await?;
+    // Taken before listing so activity during this run is picked up by the next one.
+    let run_started = format_utc(unix_now());
+
+    // With advanced search GitLab filters by language and extension itself; otherwise
+    // matches are filtered by file extension once they come back.
+    let mut blob_query = query.clone();
+    let mut lang_filter = None;
+    let mut ext_filter = None;
+    let blobs = scopes.contains(&SearchScope::Blobs);
+    let explain = args.explain || args.dry_run;
+    let advanced = if explain
+        || (blobs && (args.lang.is_some() || !args.ext.is_empty() || args.path_regex.is_some()))
+    {
+        // Only an optimisation: without it the filters still run on the results.
+        match client.supports_advanced_search().await {
+            Ok(advanced) => advanced,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Warning: {}: could not check for advanced search, filtering after searching instead: {:#}",
+                        instance_config.name, e
+                    )
+                    .yellow()
+                );
+                false
+            }
+        }
+    } else {
+        false
+    };
+    if let Some(lang) = args.lang.as_deref().filter(|_| blobs) {
+        if advanced {
+            blob_query = format!("lang:{} {}", lang, blob_query);
+        } else if totals {
+            return Err(anyhow::anyhow!(
+                "--lang cannot be combined with --totals: advanced search is not enabled on this instance"
+            ));
+        } else {
+            if !quiet {
+                println!(
+                    "{}",
+                    "Advanced search is not enabled on this instance; filtering by file extension instead"
+                        .yellow()
+                );
+            }
+            lang_filter = Some(lang_extensions(lang));
+        }
+    }
+    let extensions: Vec<String> = args
+        .ext
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect();
+    if blobs && !extensions.is_empty() {
+        // Several `extension:` filters would all have to match, so only a single one
+        // can be left to GitLab.
+        if let ([extension], true) = (extensions.as_slice(), advanced) {
+            blob_query = format!("extension:{} {}", extension, blob_query);
+        } else if totals {
+            return Err(anyhow::anyhow!(
+                "--ext cannot be combined with --totals unless it names a single extension on an instance with advanced search"
+            ));
+        } else {
+            ext_filter = Some(extensions);
+        }
+    }
+    // The regex itself is always applied to the results; GitLab only narrows them down.
+    if let Some(dir) = args
+        .path_regex
+        .as_ref()
+        .filter(|_| blobs && advanced)
+        .and_then(|regex| regex_literal_dir(regex.as_str()))
+    {
+        blob_query = format!("path:{} {}", dir, blob_query);
+    }
+    let mut list_options = ProjectListOptions {
+        include_archived: args.include_archived,
+        full: args.no_forks
+            || args.only_forks
+            || args.min_access.is_some()
+            || args.include_archived,
+        min_access_level: args.min_access.map(AccessRole::access_level),
+        exclude_subgroups: args.no_subgroups,
+        ..Default::default()
+    };
+    args.order.apply(&mut list_options);
+
+    let concurrency = args
+        .concurrency
+        .or(instance_config.concurrency)
+        .or(options.config.default_concurrency)
+        .unwrap_or(DEFAULT_CONCURRENCY);
+    let semaphore = Arc::new(Semaphore::new(concurrency as usize));
+    let mut project_ids_or_paths = options.project_ids_or_paths.clone();
+    if let Some(remote) = &options.git_remote {
+        project_ids_or_paths.push(remote.project_path(instance_config));
+    }
+
+    let mut projects_to_search = if !project_ids_or_paths.is_empty() {
+        resolve_projects(&client, &project_ids_or_paths, &semaphore).await?
+    } else if let Some(indexed) = indexed_projects(options, instance_config) {
+        if !quiet {
+            println!(
+                "Using the project index from {} ({} projects); refresh it with `index --update`",
+                indexed.updated_at,
+                indexed.projects.len()
+            );
+        }
+        indexed
+            .projects
+            .into_iter()
+            .filter(|p| args.include_archived || !p.archived)
+            .collect()
+    } else if args.all_projects || args.group.is_some() || args.namespace.is_some() {
+        let spinner = if quiet {
+            ProgressBar::hidden()
+        } else {
+            options.progress.add(ProgressBar::new_spinner())
+        };
+        spinner.set_message("Fetching projects...");
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        let listing_client = client.clone().with_listing_progress({
+            let spinner = spinner.clone();
+            move |listed| spinner.set_message(format!("Fetched {} projects...", listed))
+        });
+        let projects = list_projects(&listing_client, args.group.as_deref(), &list_options).await;
+        spinner.finish_and_clear();
+        projects?
+    } else {
+        return Err(anyhow::anyhow!(
+            "You must specify a project with --project, --group or --namespace, or use --all-projects to search in all projects"
+        ));
+    };
+
+    if args.no_forks {
+        projects_to_search.retain(|p| p.forked_from_project.is_none());
+    } else if args.only_forks {
+        projects_to_search.retain(|p| p.forked_from_project.is_some());
+    }
+    if let Some(namespace) = &args.namespace {
+        projects_to_search.retain(|p| in_namespace(&p.namespace.full_path, namespace));
+    }
+    projects_to_search = of_namespace_kind(projects_to_search, args.namespace_kind);
+    if !args.no_exclusions {
+        let total = projects_to_search.len();
+        projects_to_search.retain(|p| !instance_config.excludes(p));
+        if projects_to_search.len() < total && !quiet {
+            println!(
+                "Skipping {} projects excluded in the config (--no-exclusions to include them)",
+                total - projects_to_search.len()
+            );
+        }
+    }
+    // Listings are already filtered by GitLab, but projects given explicitly are not.
+    if let Some(role) = args.min_access {
+        projects_to_search.retain(|p| {
+            p.access_level()
+                .is_some_and(|level| level >= role.access_level())
+        });
+    }
+
+    if projects_to_search.is_empty() {
+        return Err(anyhow::anyhow!("No projects found to search in"));
+    }
+
+    // Identifies this search for the query cache and --since-last-run.
+    let search_key = format!(
+        "{}|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+        instance_config.name,
+        instance_config.url,
+        scopes
+            .iter()
+            .map(|scope| scope.api_name())
+            .collect::<Vec<_>>()
+            .join(","),
+        mr_changes,
+        blob_query,
+        args.params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&"),
+        args.tree_path.as_deref().unwrap_or_default(),
+        args.search_type.map_or("", SearchType::api_name),
+        args.label.join(","),
+        args.assignee.as_deref().unwrap_or_default()
+    );
+    let last_runs_path = last_runs_path(options.global)?;
+    let last_run_key = args.since_last_run.then(|| search_key.clone());
+    if let Some(last_run) = last_run_key
+        .as_ref()
+        .and_then(|key| LastRuns::load(&last_runs_path).runs.remove(key))
+    {
+        let total = projects_to_search.len();
+        projects_to_search
+            .retain(|p| timestamp_order_key(&p.last_activity_at) > timestamp_order_key(&last_run));
+        if !quiet {
+            println!(
+                "Skipping {} projects without activity since the last run ({})",
+                total - projects_to_search.len(),
+                last_run
+            );
+        }
+        if projects_to_search.is_empty() {
+            if !quiet {
+                println!("No projects changed since the last run");
+            }
+            record_last_run(options, last_run_key, run_started).await;
+            return Ok(None);
+        }
+    }
+
+    match args.sort_projects {
+        Some(ProjectSort::Activity) => projects_to_search.sort_by(|a, b| {
+            timestamp_order_key(&b.last_activity_at).cmp(&timestamp_order_key(&a.last_activity_at))
+        }),
+        Some(ProjectSort::Name) => projects_to_search.sort_by(|a, b| {
+            a.name_with_namespace
+                .to_lowercase()
+                .cmp(&b.name_with_namespace.to_lowercase())
+        }),
+        Some(ProjectSort::Id) => projects_to_search.sort_by_key(|p| p.id),
+        None => {}
+    }
+
+    let limited = args
+        .max_projects
+        .is_some_and(|max| projects_to_search.len() > max);
+    if let Some(max) = args.max_projects.filter(|_| limited) {
+        let total = projects_to_search.len();
+        projects_to_search.truncate(max);
+        if !quiet {
+            println!(
+                "{}",
+                format!("Limiting the search to {} of {} projects", max, total).yellow()
+            );
+        }
+    }
+
+    if explain {
+        let mut explanation = String::new();
+        writeln!(
+            explanation,
+            "Instance: {} ({})",
+            instance_config.name, instance_config.url
+        )?;
+        writeln!(
+            explanation,
+            "Scopes: {}",
+            scopes
+                .iter()
+                .map(|scope| scope.api_name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        match &args.commit {
+            Some(sha) => writeln!(explanation, "Commit: {}", sha)?,
+            None => {
+                writeln!(explanation, "Query: {}", query)?;
+                if blobs && blob_query != *query {
+                    writeln!(explanation, "Code search query: {}", blob_query)?;
+                }
+            }
+        }
+        writeln!(
+            explanation,
+            "Advanced search: {}",
+            if advanced { "enabled" } else { "not enabled" }
+        )?;
+        if let Some(search_type) = args.search_type {
+            writeln!(explanation, "Search type: {}", search_type.api_name())?;
+        }
+        for (key, value) in &args.params {
+            writeln!(explanation, "Parameter: {}={}", key, value)?;
+        }
+        for extensions in lang_filter.iter().chain(&ext_filter) {
+            writeln!(
+                explanation,
+                "Filtered after searching to extensions: {}",
+                extensions.join(", ")
+            )?;
+        }
+        if let Some(regex) = &args.path_regex {
+            writeln!(
+                explanation,
+                "Filtered after searching to paths matching: {}",
+                regex
+            )?;
+        }
+        if args.first_match_only {
+            writeln!(explanation, "Keeping only the first match in each file")?;
+        }
+        if let Some(tree_path) = &args.tree_path {
+            writeln!(
+                explanation,
+                "Searching files under {} directly at HEAD",
+                tree_path
+            )?;
+        }
+        writeln!(explanation, "Projects ({}):", projects_to_search.len())?;
+        for project in &projects_to_search {
+            writeln!(
+                explanation,
+                "  {} (ID {})",
+                project.path_with_namespace, project.id
+            )?;
+        }
+        eprint!("{}", explanation);
+        if args.dry_run {
+            return Ok(None);
+        }
+    }
+
+    if projects_to_search.len() > args.confirm_above && !args.yes {
+        let _prompt = options.prompt_lock.lock().await;
+        confirm_search(
+            &options.progress,
+            &instance_config.name,
+            projects_to_search.len(),
+        )?;
+    }
+
+    let state_file = &args.state_file;
+    let state = match state_file {
+        Some(path) => {
+            let state = match load_search_state(path)? {
+                Some(state) if state.instance != instance_config.name || &state.query != query => {
+                    return Err(anyhow::anyhow!(
+                        "State file {} belongs to a different search (instance '{}', query '{}')",
+                        path.display(),
+                        state.instance,
+                        state.query
+                    ));
+                }
+                Some(state) => state,
+                None => SearchState {
+                    instance: instance_config.name.clone(),
+                    query: query.clone(),
+                    searched_project_ids: HashSet::new(),
+                },
+            };
+
+            let total = projects_to_search.len();
+            projects_to_search.retain(|p| !state.searched_project_ids.contains(&p.id));
+            if projects_to_search.len() < total && !quiet {
+                println!(
+                    "Resuming search: skipping {} already searched projects",
+                    total - projects_to_search.len()
+                );
+            }
+            if projects_to_search.is_empty() {
+                if !quiet {
+                    println!("All projects were already searched");
+                }
+                std::fs::remove_file(path)?;
+                return Ok(None);
+            }
+
+            Some(Arc::new(Mutex::new(state)))
+        }
+        None => None,
+    };
+
+    if !quiet {
+        match &args.commit {
+            Some(sha) => println!("Looking for commit: {}", sha.cyan()),
+            None => println!("Searching for: {}", query.cyan()),
+        }
+        println!("Searching in {} projects...", projects_to_search.len());
+    }
+
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let project_totals = Arc::new(Mutex::new(Vec::new()));
+    let commit_presence = Arc::new(Mutex::new(Vec::new()));
+    let failed = Arc::new(Mutex::new(false));
+    // Errors that affect every project of the instance, reported once after the search
+    let instance_errors = Arc::new(Mutex::new(BTreeSet::new()));
+    let completed = Arc::new(Mutex::new(Vec::new()));
+    let errored = Arc::new(Mutex::new(Vec::new()));
+    let eta = Arc::new(Mutex::new(RollingEta::default()));
+
+    // Reuse the results of an identical recent search. Totals and resumable searches
+    // always go to the API.
+    let query_cache_key =
+        (!args.no_cache && !totals && args.commit.is_none() && state_file.is_none()).then(|| {
+            let mut project_ids: Vec<u64> = projects_to_search.iter().map(|p| p.id).collect();
+            project_ids.sort_unstable();
+            format!(
+                "{}|{}",
+                search_key,
+                project_ids
+                    .iter()
+                    .map(u64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+        });
+    let query_cache_path = query_cache_path(options.global)?;
+    let cached_results = match &query_cache_key {
+        Some(key) if !args.refresh => QueryCache::load(&query_cache_path)
+            .entries
+            .remove(key)
+            .map(|entry| entry.results),
+        _ => None,
+    };
+    let from_cache = cached_results.is_some();
+    let mut interrupted = false;
+
+    if let Some(cached_results) = cached_results {
+        *results.lock().await = cached_results;
+        completed
+            .lock()
+            .await
+            .extend(projects_to_search.iter().cloned());
+    } else {
+        let pb = if quiet {
+            ProgressBar::hidden()
+        } else {
+            options
+                .progress
+                .add(ProgressBar::new(projects_to_search.len() as u64))
+        };
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+
+        let tasks = projects_to_search.iter().map(|project| {
+            let client = client.clone();
+            let query = query.clone();
+            let project = project.clone();
+            let results = Arc::clone(&results);
+            let project_totals = Arc::clone(&project_totals);
+            let commit_presence = Arc::clone(&commit_presence);
+            let commit = args.commit.clone();
+            let failed = Arc::clone(&failed);
+            let instance_errors = Arc::clone(&instance_errors);
+            let completed = Arc::clone(&completed);
+            let errored = Arc::clone(&errored);
+            let state = state.clone();
+            let state_file = state_file.clone();
+            let pb = pb.clone();
+            let eta = Arc::clone(&eta);
+            let scopes = scopes.clone();
+            let blob_query = blob_query.clone();
+            let tree_path = args.tree_path.clone();
+            let (max_results, truncated) = (args.max_results, &options.truncated);
+            let project_timeout = args.per_project_timeout.map(Duration::from_secs);
+            let wait_index = args.wait_index.map(Duration::from_secs);
+            let semaphore = Arc::clone(&semaphore);
+
+            async move {
+                let Ok(_permit) = semaphore.acquire().await else {
+                    return;
+                };
+                if truncated.load(Ordering::Relaxed) {
+                    return;
+                }
+                let started = Instant::now();
+                let found = AtomicUsize::new(0);
+                let outcomes: Vec<(&str, Result<()>)> = if let Some(sha) = &commit {
+                    let has_commit = client.has_commit(project.id, sha);
+                    let outcome = match within(project_timeout, has_commit).await {
+                        Ok(present) => {
+                            commit_presence
+                                .lock()
+                                .await
+                                .push((project.clone(), present));
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    };
+                    vec![("commit", outcome)]
+                } else {
+                    let outcomes = join_all(scopes.iter().map(|&scope| {
+                        let query = if scope == SearchScope::Blobs {
+                            &blob_query
+                        } else {
+                            &query
+                        };
+                        let (client, project) = (&client, &project);
+                        let (results, project_totals) = (&results, &project_totals);
+                        let tree_path = tree_path.as_deref();
+                        let found = &found;
+                        within(project_timeout, async move {
+                            if totals {
+                                let total = client
+                                    .count_matches(project.id, query, scope.api_name())
+                                    .await?;
+                                project_totals
+                                    .lock()
+                                    .await
+                                    .push((project.clone(), scope, total));
+                            } else {
+                                let search = || {
+                                    search_project(
+                                        client, project.id, query, scope, mr_changes, tree_path,
+                                    )
+                                };
+                                let project_results = match wait_index
+                                    .filter(|_| scope == SearchScope::Blobs && tree_path.is_none())
+                                {
+                                    Some(wait) => {
+                                        wait_for_index(wait, recently_active(project), search)
+                                            .await?
+                                    }
+                                    None => search().await?,
+                                };
+                                let mut results_guard = results.lock().await;
+                                for result in project_results {
+                                    let collected =
+                                        options.collected.fetch_add(1, Ordering::Relaxed);
+                                    if max_results.is_some_and(|max| collected >= max) {
+                                        truncated.store(true, Ordering::Relaxed);
+                                        break;
+                                    }
+                                    results_guard.push((project.clone(), result));
+                                    found.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            Ok::<_, anyhow::Error>(())
+                        })
+                    }))
+                    .await;
+                    scopes
+                        .iter()
+                        .map(|scope| scope.api_name())
+                        .zip(outcomes)
+                        .collect()
+                };
+
+                let mut searched = true;
+                let mut errors = Vec::new();
+                for (what, outcome) in outcomes {
+                    if let Err(e) = outcome {
+                        searched = false;
+                        errors.push(format!("{}: {:#}", what, e));
+                        *failed.lock().await = true;
+                        if e.downcast_ref::<BlobSearchUnavailable>().is_some()
+                            || e.downcast_ref::<SearchTermTooShort>().is_some()
+                        {
+                            instance_errors.lock().await.insert(e.to_string());
+                            continue;
+                        }
+                        eprintln!(
+                            "Error searching {} in project {}: {}",
+                            what, project.name_with_namespace, e
+                        );
+                    }
+                }
+                log_event(
+                    "project",
+                    serde_json::json!({
+                        "instance": client.config().name,
+                        "project": project.path_with_namespace,
+                        "project_id": project.id,
+                        "results": found.load(Ordering::Relaxed),
+                        "duration_ms": started.elapsed().as_millis() as u64,
+                        "errors": errors,
+                    }),
+                );
+                if searched {
+                    completed.lock().await.push(project.clone());
+                    if let (Some(state), Some(path)) = (state, state_file) {
+                        let mut state = state.lock().await;
+                        state.searched_project_ids.insert(project.id);
+                        if let Err(e) = save_search_state(&path, &state) {
+                            eprintln!("Warning: {}", e);
+                        }
+                    }
+                } else {
+                    errored.lock().await.push(project.clone());
+                }
+                pb.inc(1);
+                let mut eta = eta.lock().await;
+                eta.record(started.elapsed());
+                let remaining = pb.length().unwrap_or(0).saturating_sub(pb.position());
+                let estimate = eta.message(remaining);
+                if estimate.is_empty() {
+                    pb.set_message(format!("Searching in {}", project.name_with_namespace));
+                } else {
+                    pb.set_message(format!(
+                        "{} | Searching in {}",
+                        estimate, project.name_with_namespace
+                    ));
+                }
+            }
+        });
+
+        interrupted = tokio::select! {
+            _ = join_all(tasks) => false,
+            _ = tokio::signal::ctrl_c() => true,
+        };
+
+        if interrupted {
+            pb.abandon_with_message("Search interrupted");
+            if let Some(path) = state_file {
+                eprintln!(
+                    "Progress saved to {}; re-run with the same --state-file to resume",
+                    path.display()
+                );
+            }
+        } else {
+            pb.finish_with_message("Search completed");
+            // A finished search with no failures has nothing left to resume.
+            if let Some(path) = state_file {
+                if !*failed.lock().await && !options.truncated.load(Ordering::Relaxed) {
+                    std::fs::remove_file(path).ok();
+                }
+            }
+        }
+
+        for error in instance_errors.lock().await.iter() {
+            eprintln!(
+                "{}",
+                format!("Error: {}: {}", instance_config.name, error).red()
+            );
+        }
+
+        let complete =
+            !interrupted && !*failed.lock().await && !options.truncated.load(Ordering::Relaxed);
+        if let Some(key) = query_cache_key.filter(|_| complete) {
+            options.cache_updates.lock().await.push((
+                key,
+                CachedQuery {
+                    saved_at: unix_now(),
+                    results: results.lock().await.clone(),
+                },
+            ));
+        }
+    }
+
+    // Only a complete search may move the mark forward, or failed projects would be
+    // skipped from now on.
+    if !interrupted
+        && !limited
+        && !*failed.lock().await
+        && !options.truncated.load(Ordering::Relaxed)
+    {
+        record_last_run(options, last_run_key, run_started).await;
+    }
+
+    let mut results = std::mem::take(&mut *results.lock().await);
+    // Per-project searches report the searched project; only cross-project matches
+    // need their project looked up.
+    if results
+        .iter()
+        .any(|(project, hit)| reported_elsewhere(project, hit))
+    {
+        relabel_result_projects(&client, &projects_to_search, &mut results).await;
+    }
+    if !args.no_merge_chunks {
+        results = merge_overlapping_chunks(results);
+    }
+    for extensions in lang_filter.iter().chain(&ext_filter) {
+        results.retain(|(_, hit)| match hit {
+            SearchHit::Blob(result) => has_extension(&result.path, extensions),
+            SearchHit::MergeRequest { .. } | SearchHit::Issue(_) | SearchHit::WikiBlob(_) => true,
+        });
+    }
+    if let Some(regex) = &args.path_regex {
+        results.retain(|(_, hit)| match hit {
+            SearchHit::Blob(result) => regex.is_match(&result.path),
+            SearchHit::MergeRequest { .. } | SearchHit::Issue(_) | SearchHit::WikiBlob(_) => true,
+        });
+    }
+    if args.literal {
+        results.retain(|(_, hit)| match hit {
+            SearchHit::Blob(result) => result.data.contains(query.as_str()),
+            SearchHit::MergeRequest { .. } | SearchHit::Issue(_) | SearchHit::WikiBlob(_) => true,
+        });
+    }
+    if args.first_match_only {
+        results = first_match_per_file(results);
+    }
+
+    let totals = std::mem::take(&mut *project_totals.lock().await);
+    let commit_presence = std::mem::take(&mut *commit_presence.lock().await);
+    let completed = std::mem::take(&mut *completed.lock().await);
+    let errored = std::mem::take(&mut *errored.lock().await);
+    Ok(Some(InstanceSearch {
+        client,
+        semaphore,
+        results,
+        totals,
+        commit_presence,
+        completed,
+        errored,
+        from_cache,
+        interrupted,
+    }))
+}
+
+/// Writes the labels and assignees of an issue or merge request, if it has any.
+fn write_work_item_details(
+    out: &mut String,
+    labels: &[String],
+    assignees: &[Author],
+) -> Result<()> {
+    if !labels.is_empty() {
+        writeln!(out, "  labels: {}", labels.join(", ").magenta())?;
+    }
+    if !assignees.is_empty() {
+        let assignees: Vec<String> = assignees
+            .iter()
+            .map(|assignee| format!("@{}", assignee.username))
+            .collect();
+        writeln!(out, "  assigned to {}", assignees.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Renders `hit` as a single `instance/project:path:line: snippet` line. Code and wiki
+/// matches show the first snippet line containing the query, leading whitespace trimmed.
+fn render_compact(
+    instance: &str,
+    project: &Project,
+    hit: &SearchHit,
+    query: &str,
+    with_column: bool,
+    render_options: &RenderOptions,
+) -> String {
+    let location = format!("{}/{}", instance, project.path_with_namespace);
+    let (path, startline, data) = match hit {
+        SearchHit::Blob(result) => (&result.path, result.startline, &result.data),
+        SearchHit::WikiBlob(result) => (&result.path, result.startline, &result.data),
+        SearchHit::MergeRequest { merge_request, .. } => {
+            return format!(
+                "{}:!{}: {}\n",
+                location.green(),
+                merge_request.iid,
+                render_options.clean(&merge_request.title)
+            );
+        }
+        SearchHit::Issue(issue) => {
+            return format!(
+                "{}:#{}: {}\n",
+                location.green(),
+                issue.iid,
+                render_options.clean(&issue.title)
+            );
+        }
+    };
+    let lines: Vec<&str> = data.lines().collect();
+    let (offset, column) = match locate_match(data, query, render_options) {
+        Some((offset, column)) => (offset, column),
+        None => (
+            lines
+                .iter()
+                .position(|line| render_options.keeps_line(line))
+                .unwrap_or(0),
+            1,
+        ),
+    };
+    let line = lines.get(offset).copied().unwrap_or_default();
+    let column = if with_column {
+        format!("{}:", column)
+    } else {
+        String::new()
+    };
+    format!(
+        "{}:{}:{}:{} {}\n",
+        location.green(),
+        path.cyan(),
+        (startline + offset as u64).to_string().yellow(),
+        column,
+        render_options.clean(line.trim_start())
+    )
+}
+
+/// Finds the first snippet line `render_options` keeps that contains `query`, ignoring
+/// case. Returns its index and the 1-based byte column of the match, as ripgrep's
+/// `--column` reports it.
+fn locate_match(data: &str, query: &str, render_options: &RenderOptions) -> Option<(usize, usize)> {
+    let needle = query.to_lowercase();
+    let starts_with_needle = |text: &str| {
+        let mut chars = text.chars().flat_map(char::to_lowercase);
+        needle.chars().all(|c| chars.next() == Some(c))
+    };
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| render_options.keeps_line(line))
+        .find_map(|(offset, line)| {
+            line.char_indices()
+                .find(|&(at, _)| starts_with_needle(&line[at..]))
+                .map(|(at, _)| (offset, at + 1))
+        })
+}
+
+/// Identifies a matched file: instance name, project ID, path and ref.
+type FileKey<'a> = (&'a str, u64, &'a str, &'a str);
+
+/// Renders search hits as text, with whatever extras (previews, context, blame) were fetched.
+struct HitRenderer<'a> {
+    /// With `--compact`, the query used to pick the line shown for each match
+    compact_query: Option<&'a str>,
+    /// With `--compact`, also print the column of each match
+    column: bool,
+    /// Prefix each result with its instance, as they come from several
+    label_instances: bool,
+    label_scopes: bool,
+    template: Option<&'a [TemplatePart]>,
+    render_options: &'a RenderOptions,
+    preview_head: Option<usize>,
+    context: Option<u64>,
+    file_contents: &'a HashMap<FileKey<'a>, Result<String>>,
+    blames: &'a HashMap<FileKey<'a>, Result<Vec<BlameRange>>>,
+    /// Leave the project out of code match headers, as they all share one
+    omit_project: bool,
+}
+
+impl HitRenderer<'_> {
+    fn render(&self, instance: &str, project: &Project, hit: &SearchHit) -> Result<String> {
+        let render_options = self.render_options;
+        if let Some(query) = self.compact_query {
+            return Ok(render_compact(
+                instance,
+                project,
+                hit,
+                query,
+                self.column,
+                render_options,
+            ));
+        }
+        let mut out = String::new();
+        let mut label = String::new();
+        if self.label_instances {
+            write!(label, "[{}] ", instance)?;
+        }
+        if self.label_scopes {
+            write!(label, "[{}] ", hit.scope().api_name())?;
+        }
+        let result = match hit {
+            SearchHit::Blob(result) => result,
+            SearchHit::MergeRequest {
+                merge_request,
+                matching_files,
+            } => {
+                writeln!(
+                    out,
+                    "\n{}{} - !{} {} [{}]",
+                    label,
+                    project_heading(project).green(),
+                    merge_request.iid.to_string().cyan(),
+                    merge_request.title,
+                    merge_request.state.yellow()
+                )?;
+                writeln!(
+                    out,
+                    "  {} -> {} by {} (@{})",
+                    merge_request.source_branch,
+                    merge_request.target_branch,
+                    merge_request.author.name,
+                    merge_request.author.username
+                )?;
+                write_work_item_details(&mut out, &merge_request.labels, &merge_request.assignees)?;
+                writeln!(out, "  {}", merge_request.web_url)?;
+                for file in matching_files {
+                    writeln!(out, "  changed: {}", file.cyan())?;
+                }
+                return Ok(out);
+            }
+            SearchHit::Issue(issue) => {
+                writeln!(
+                    out,
+                    "\n{}{} - #{} {} [{}]",
+                    label,
+                    project_heading(project).green(),
+                    issue.iid.to_string().cyan(),
+                    issue.title,
+                    issue.state.yellow()
+                )?;
+                writeln!(
+                    out,
+                    "  by {} (@{})",
+                    issue.author.name, issue.author.username
+                )?;
+                write_work_item_details(&mut out, &issue.labels, &issue.assignees)?;
+                writeln!(out, "  {}", issue.web_url)?;
+                return Ok(out);
+            }
+            SearchHit::WikiBlob(result) => {
+                writeln!(
+                    out,
+                    "\n{}{} - wiki {}:{}",
+                    label,
+                    project_heading(project).green(),
+                    result.path.cyan(),
+                    result.startline.to_string().yellow()
+                )?;
+                writeln!(out, "  {}", wiki_url(project, result))?;
+                for (i, line) in result.data.lines().enumerate() {
+                    if render_options.keeps_line(line) {
+                        writeln!(
+                            out,
+                            "{}: {}",
+                            (result.startline + i as u64).to_string().yellow(),
+                            render_options.clean(line)
+                        )?;
+                    }
+                }
+                return Ok(out);
+            }
+        };
+
+        if let Some(template) = self.template {
+            writeln!(
+                out,
+                "{}",
+                render_template(template, instance, project, result, render_options)
+            )?;
+            return Ok(out);
+        }
+
+        if self.omit_project {
+            writeln!(
+                out,
+                "\n{}{}:{}",
+                label,
+                result.path.cyan(),
+                result.startline.to_string().yellow()
+            )?;
+        } else {
+            writeln!(
+                out,
+                "\n{}{} - {}:{}",
+                label,
+                project_heading(project).green(),
+                result.path.cyan(),
+                result.startline.to_string().yellow()
+            )?;
+        }
+
+        let file = (
+            instance,
+            project.id,
+            result.path.as_str(),
+            result.ref_field.as_str(),
+        );
+        match self.blames.get(&file) {
+            Some(Ok(ranges)) => {
+                if let Some(commit) = blame_line(ranges, result.startline) {
+                    writeln!(
+                        out,
+                        "{}",
+                        format!(
+                            "  last changed in {} by {} on {}",
+                            &commit.id[..commit.id.len().min(8)],
+                            render_options.clean(&commit.author_name),
+                            commit.authored_date
+                        )
+                        .dimmed()
+                    )?;
+                }
+            }
+            Some(Err(e)) => writeln!(out, "{}", format!("  (blame unavailable: {})", e).dimmed())?,
+            None => {}
+        }
+        let contents = self.file_contents.get(&file);
+        if let (Some(head), Some(contents)) = (self.preview_head, contents) {
+            match contents {
+                Ok(contents) => {
+                    let lines: Vec<&str> = contents.lines().take(head).collect();
+                    for (i, line) in lines.iter().enumerate() {
+                        writeln!(
+                            out,
+                            "{}",
+                            format!("{:>4} | {}", i + 1, render_options.clean(line)).dimmed()
+                        )?;
+                    }
+                    if lines.len() == head {
+                        writeln!(out, "{}", "     ...".dimmed())?;
+                    }
+                }
+                Err(e) => writeln!(
+                    out,
+                    "{}",
+                    format!("  (preview unavailable: {})", e).dimmed()
+                )?,
+            }
+        }
+
+        // With context, print the lines around the match from the file itself; the snippet
+        // GitLab returned is the fallback when the file could not be fetched.
+        // Lines outside `matched` are context.
+        let (first_line, lines, matched): (u64, Vec<&str>, Option<RangeInclusive<u64>>) =
+            match (self.context, contents) {
+                // A file shorter than the match has changed since it was indexed.
+                (Some(context), Some(Ok(contents)))
+                    if contents.lines().count() as u64 >= chunk_end(result) =>
+                {
+                    let first_line = result.startline.saturating_sub(context).max(1);
+                    let last_line = chunk_end(result) + context;
+                    let lines = contents
+                        .lines()
+                        .skip(first_line as usize - 1)
+                        .take((last_line + 1 - first_line) as usize)
+                        .collect();
+                    (
+                        first_line,
+                        lines,
+                        Some(result.startline..=chunk_end(result)),
+                    )
+                }
+                (Some(_), Some(Err(e))) => {
+                    writeln!(
+                        out,
+                        "{}",
+                        format!("  (context unavailable: {})", e).dimmed()
+                    )?;
+                    (result.startline, result.data.lines().collect(), None)
+                }
+                _ => (result.startline, result.data.lines().collect(), None),
+            };
+        let Some(matched) = matched else {
+            for (i, line) in lines.into_iter().enumerate() {
+                if !render_options.keeps_line(line) {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{}: {}",
+                    (first_line + i as u64).to_string().yellow(),
+                    render_options.clean(line)
+                )?;
+            }
+            return Ok(out);
+        };
+
+        // Like ripgrep: line numbers in an aligned gutter, ":" after matched lines and
+        // "-" after context lines, with the context dimmed.
+        let width = (first_line + lines.len().saturating_sub(1) as u64)
+            .to_string()
+            .len();
+        for (i, line) in lines.into_iter().enumerate() {
+            if !render_options.keeps_line(line) {
+                continue;
+            }
+            let number = first_line + i as u64;
+            let line = render_options.clean(line);
+            if matched.contains(&number) {
+                writeln!(
+                    out,
+                    "{}{} {}",
+                    format!("{:>width$}", number).yellow().bold(),
+                    ":".yellow(),
+                    line.bold()
+                )?;
+            } else {
+                writeln!(out, "{}", format!("{:>width$}- {}", number, line).dimmed())?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Where `--output-dir` puts a project's results: one subdirectory per path segment (under
+/// the instance name when several instances are searched), with each segment restricted to
+/// characters that are safe in file names.
+fn project_output_path(
+    dir: &Path,
+    instance: Option<&str>,
+    project_path: &str,
+    extension: &str,
+) -> PathBuf {
+    let mut segments: Vec<String> = instance
+        .into_iter()
+        .chain(project_path.split('/'))
+        .map(|segment| {
+            let clean: String = segment
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            // Empty, `.` and `..` segments would escape or collapse the tree.
+            if clean.chars().all(|c| c == '.') {
+                "_".to_string()
+            } else {
+                clean
+            }
+        })
+        .collect();
+    if let Some(last) = segments.last_mut() {
+        last.push('.');
+        last.push_str(extension);
+    }
+    segments
+        .into_iter()
+        .fold(dir.to_path_buf(), |path, segment| path.join(segment))
+}
+
+/// The info string of a Markdown code fence for a file, inferred from its extension.
+fn fence_language(path: &str) -> &'static str {
+    const LANGUAGES: [&str; 22] = [
+        "rust",
+        "python",
+        "javascript",
+        "typescript",
+        "go",
+        "java",
+        "kotlin",
+        "scala",
+        "ruby",
+        "php",
+        "c",
+        "cpp",
+        "csharp",
+        "swift",
+        "shell",
+        "yaml",
+        "json",
+        "markdown",
+        "html",
+        "css",
+        "sql",
+        "hcl",
+    ];
+    LANGUAGES
+        .into_iter()
+        .find(|lang| has_extension(path, &lang_extensions(lang)))
+        .unwrap_or("")
+}
+
+/// Writes a snippet as a Markdown code block, highlighted by the file's language.
+fn write_fenced_snippet(
+    out: &mut String,
+    path: &str,
+    data: &str,
+    render_options: &RenderOptions,
+) -> std::fmt::Result {
+    let lines: Vec<Cow<str>> = data
+        .lines()
+        .filter(|line| render_options.keeps_line(line))
+        .map(|line| render_options.clean(line))
+        .collect();
+    // A fence longer than any backtick run in the snippet cannot be closed by it.
+    let longest_run = lines
+        .iter()
+        .flat_map(|line| line.split(|c| c != '`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    writeln!(out, "{}{}", fence, fence_language(path))?;
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    writeln!(out, "{}", fence)
+}
+
+/// Renders results as a Markdown document: a heading per project, with a link and a
+/// fenced snippet for each code match and a linked list entry for merge requests and issues.
+fn render_markdown(
+    query: &str,
+    results: &[(&InstanceSearch, &Project, &SearchHit)],
+    multi_instance: bool,
+    render_options: &RenderOptions,
+) -> Result<String> {
+    let mut out = String::new();
+    let projects: HashSet<(&str, u64)> = results
+        .iter()
+        .map(|(search, project, _)| (search.client.config().name.as_str(), project.id))
+        .collect();
+    writeln!(
+        out,
+        "# Search results for `{}`
+",
+        query.replace('`', "'")
+    )?;
+    writeln!(
+        out,
+        "{} results across {} projects.",
+        results.len(),
+        projects.len()
+    )?;
+
+    // Keep each project's results together, in the order projects first appear.
+    let mut groups: Vec<(&InstanceSearch, &Project, Vec<&SearchHit>)> = Vec::new();
+    for &(search, project, hit) in results {
+        let instance = search.client.config().name.as_str();
+        match groups
+            .iter_mut()
+            .find(|(s, p, _)| s.client.config().name.as_str() == instance && p.id == project.id)
+        {
+            Some((_, _, hits)) => hits.push(hit),
+            None => groups.push((search, project, vec![hit])),
+        }
+    }
+
+    for (search, project, hits) in groups {
+        let instance = if multi_instance {
+            format!(" ({})", search.client.config().name)
+        } else {
+            String::new()
+        };
+        writeln!(
+            out,
+            "\n## [{}]({}){}",
+            project.path_with_namespace, project.web_url, instance
+        )?;
+        for hit in hits {
+            match hit {
+                SearchHit::Blob(result) => {
+                    writeln!(
+                        out,
+                        "\n[{}:{}]({})\n",
+                        result.path,
+                        result.startline,
+                        blob_url(project, result)
+                    )?;
+                    write_fenced_snippet(&mut out, &result.path, &result.data, render_options)?;
+                }
+                SearchHit::WikiBlob(result) => {
+                    writeln!(
+                        out,
+                        "\n[wiki {}:{}]({})\n",
+                        result.path,
+                        result.startline,
+                        wiki_url(project, result)
+                    )?;
+                    write_fenced_snippet(&mut out, &result.path, &result.data, render_options)?;
+                }
+                SearchHit::MergeRequest {
+                    merge_request,
+                    matching_files,
+                } => {
+                    writeln!(
+                        out,
+                        "\n- [!{} {}]({}) ({})",
+                        merge_request.iid,
+                        merge_request.title,
+                        merge_request.web_url,
+                        merge_request.state
+                    )?;
+                    write_markdown_work_item_details(
+                        &mut out,
+                        &merge_request.labels,
+                        &merge_request.assignees,
+                    )?;
+                    for file in matching_files {
+                        writeln!(out, "  - changed: `{}`", file)?;
+                    }
+                }
+                SearchHit::Issue(issue) => {
+                    writeln!(
+                        out,
+                        "\n- [#{} {}]({}) ({})",
+                        issue.iid, issue.title, issue.web_url, issue.state
+                    )?;
+                    write_markdown_work_item_details(&mut out, &issue.labels, &issue.assignees)?;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn write_markdown_work_item_details(
+    out: &mut String,
+    labels: &[String],
+    assignees: &[Author],
+) -> Result<()> {
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter().map(|label| format!("`{}`", label)).collect();
+        writeln!(out, "  - labels: {}", labels.join(", "))?;
+    }
+    if !assignees.is_empty() {
+        let assignees: Vec<String> = assignees
+            .iter()
+            .map(|assignee| format!("@{}", assignee.username))
+            .collect();
+        writeln!(out, "  - assigned to {}", assignees.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Writes each file's contents, creating directories as needed.
+fn write_output_files(files: &BTreeMap<PathBuf, String>) -> Result<()> {
+    for (path, contents) in files {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// A query read from stdin or a file, without the trailing newline that usually ends it.
+fn read_query(mut query: String, source: &str) -> Result<String> {
+    let len = query.trim_end_matches(['\n', '\r']).len();
+    query.truncate(len);
+    if query.trim().is_empty() {
+        return Err(anyhow::anyhow!("The query read from {} is empty", source));
+    }
+    Ok(query)
+}
+
+async fn handle_search_command(args: SearchArgs, global: &GlobalArgs) -> Result<()> {
+    let started = Instant::now();
+    let SearchArgs {
+        query,
+        query_file,
+        commit,
+        instance,
+        parallel_instances,
+        project,
+        input_file,
+        from_git,
+        template,
+        state_file,
+        scope: scopes,
+        mr_changes,
+        totals,
+        show_empty,
+        by_namespace,
+        quiet,
+        sorted_stable,
+        diff_baseline,
+        show_removed,
+        summary_json,
+        output_dir,
+        raw,
+        preview_head,
+        context,
+        blame,
+        min_line_len,
+        max_line_len,
+        format,
+        include_data,
+        ..
+    } = args.clone();
+    // The state file records what was looked for, so commit checks get a query of their own.
+    let query = match (query, &query_file, &commit) {
+        (Some(query), _, _) if query == "-" => {
+            let mut query = String::new();
+            std::io::stdin()
+                .read_to_string(&mut query)
+                .context("Failed to read the query from stdin")?;
+            read_query(query, "stdin")?
+        }
+        (Some(query), _, _) => query,
+        (None, Some(path), _) => {
+            let query = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read query file {}", path.display()))?;
+            read_query(query, &path.display().to_string())?
+        }
+        (None, None, Some(sha)) => format!("commit:{}", sha),
+        (None, None, None) => {
+            unreachable!("clap requires --query unless --query-file or --commit is given")
+        }
+    };
+    if commit.is_none() && query.trim().chars().count() < MIN_SEARCH_TERM_LEN {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: GitLab may reject or find nothing for search terms shorter than {} characters",
+                MIN_SEARCH_TERM_LEN
+            )
+            .yellow()
+        );
+    }
+    // Keep stdout valid JSON (or reproducible) by dropping the status chatter.
+    let quiet = quiet || sorted_stable || args.compact || format != OutputFormat::Text;
+    // Result files should not contain terminal color codes.
+    if sorted_stable || output_dir.is_some() {
+        colored::control::set_override(false);
+    }
+
+    if template.is_some() && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--template cannot be combined with --format {}",
+            format.name()
+        ));
+    }
+    if format == OutputFormat::Markdown
+        && (totals || commit.is_some() || show_empty || show_removed)
+    {
+        return Err(anyhow::anyhow!(
+            "--format markdown cannot be combined with --totals, --commit, --show-empty or --show-removed"
+        ));
+    }
+    let mut seen_scopes = HashSet::new();
+    let scopes: Vec<SearchScope> = scopes
+        .into_iter()
+        .filter(|scope| seen_scopes.insert(*scope))
+        .collect();
+    // Label each result with its scope when they are mixed in one listing.
+    let label_scopes = scopes.len() > 1;
+
+    if template.is_some() && scopes != [SearchScope::Blobs] {
+        return Err(anyhow::anyhow!(
+            "--template is only supported with --scope blobs"
+        ));
+    }
+    if totals && template.is_some() {
+        return Err(anyhow::anyhow!(
+            "--totals cannot be combined with --template"
+        ));
+    }
+    if show_empty && format == OutputFormat::Json && !totals {
+        return Err(anyhow::anyhow!(
+            "--show-empty with --format json requires --totals, whose output includes zero counts"
+        ));
+    }
+    if by_namespace && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--by-namespace cannot be combined with --format {}",
+            format.name()
+        ));
+    }
+    if preview_head.is_some() && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--preview-head cannot be combined with --format {}",
+            format.name()
+        ));
+    }
+    if context.is_some() && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--context cannot be combined with --format {}",
+            format.name()
+        ));
+    }
+    if args.compact && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--compact cannot be combined with --format {}",
+            format.name()
+        ));
+    }
+    if blame && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!(
+            "--blame cannot be combined with --format {}",
+            format.name()
+        ));
+    }
+    if (!args.label.is_empty() || args.assignee.is_some())
+        && !scopes
+            .iter()
+            .any(|scope| matches!(scope, SearchScope::Issues | SearchScope::MergeRequests))
+    {
+        return Err(anyhow::anyhow!(
+            "--label and --assignee require --scope issues or merge_requests"
+        ));
+    }
+    if mr_changes && !scopes.contains(&SearchScope::MergeRequests) {
+        return Err(anyhow::anyhow!(
+            "--mr-changes requires --scope merge_requests"
+        ));
+    }
+    if diff_baseline.is_some() && scopes != [SearchScope::Blobs] {
+        return Err(anyhow::anyhow!(
+            "--diff-baseline is only supported with --scope blobs"
+        ));
+    }
+    let baseline = diff_baseline.as_deref().map(load_baseline).transpose()?;
+    let template = template.as_deref().map(parse_template).transpose()?;
+    let mut config = get_config(global).await?;
+    let instance = match args.ad_hoc.apply(&mut config)? {
+        Some(name) => vec![name],
+        None => instance,
+    };
+    let git_remote = if from_git {
+        Some(origin_remote()?)
+    } else {
+        None
+    };
+
+    let instances = select_instances(&config, &instance, git_remote.as_ref())?;
+    log_event(
+        "search_started",
+        serde_json::json!({
+            "query": query,
+            "instances": instances.iter().map(|i| &i.name).collect::<Vec<_>>(),
+            "scopes": scopes.iter().map(|scope| scope.api_name()).collect::<Vec<_>>(),
+        }),
+    );
+    if instances.len() > 1 && state_file.is_some() {
+        return Err(anyhow::anyhow!(
+            "--state-file can only be used when searching a single instance"
+        ));
+    }
+    if instances.len() > 1 && git_remote.is_some() {
+        return Err(anyhow::anyhow!(
+            "--from-git can only be used when searching a single instance"
+        ));
+    }
+
+    let mut project_ids_or_paths = project;
+    if let Some(path) = &input_file {
+        project_ids_or_paths.extend(read_project_list(path)?);
+    }
+
+    let options = SearchOptions {
+        args: &args,
+        global,
+        config: &config,
+        query: query.clone(),
+        scopes: scopes.clone(),
+        quiet,
+        project_ids_or_paths,
+        git_remote,
+        progress: MultiProgress::new(),
+        prompt_lock: Mutex::new(()),
+        collected: AtomicUsize::new(0),
+        truncated: AtomicBool::new(false),
+        cache_updates: Mutex::new(Vec::new()),
+        last_run_updates: Mutex::new(Vec::new()),
+    };
+
+    // Each instance has its own concurrency budget; instances themselves are searched
+    // side by side, or one after the other to respect their separate rate limits.
+    let outcomes = if parallel_instances {
+        join_all(
+            instances
+                .iter()
+                .map(|instance| search_instance(&options, instance)),
+        )
+        .await
+    } else {
+        let mut outcomes = Vec::new();
+        for instance in &instances {
+            let outcome = search_instance(&options, instance).await;
+            let interrupted = matches!(&outcome, Ok(Some(search)) if search.interrupted);
+            outcomes.push(outcome);
+            if interrupted {
+                break;
+            }
+        }
+        outcomes
+    };
+    QueryCache::save_updates(
+        &query_cache_path(global)?,
+        std::mem::take(&mut *options.cache_updates.lock().await),
+    );
+    LastRuns::save_updates(
+        &last_runs_path(global)?,
+        std::mem::take(&mut *options.last_run_updates.lock().await),
+    );
+
+    let mut searches = Vec::new();
+    let mut failed_instances = 0;
+    for (instance, outcome) in instances.iter().zip(outcomes) {
+        match outcome {
+            Ok(Some(search)) => searches.push(search),
+            Ok(None) => {}
+            Err(e) if instances.len() == 1 => return Err(e),
+            // One unreachable instance shouldn't hide the results of the others.
+            Err(e) => {
+                failed_instances += 1;
+                log_event(
+                    "instance_failed",
+                    serde_json::json!({ "instance": instance.name, "error": format!("{:#}", e) }),
+                );
+                eprintln!(
+                    "{}",
+                    format!("Error searching instance {}: {:#}", instance.name, e).red()
+                );
+            }
+        }
+    }
+    log_event(
+        "search_finished",
+        serde_json::json!({
+            "results": searches.iter().map(|search| search.results.len()).sum::<usize>(),
+            "failed_instances": failed_instances,
+            "interrupted": searches.iter().any(|search| search.interrupted),
+            "duration_ms": started.elapsed().as_millis() as u64,
+        }),
+    );
+    if failed_instances == instances.len() {
+        return Err(anyhow::anyhow!("The search failed on every instance"));
+    }
+    if searches.is_empty() {
+        return Ok(());
+    }
+    if let Some(max) = args
+        .max_results
+        .filter(|_| options.truncated.load(Ordering::Relaxed))
+    {
+        eprintln!(
+            "{}",
+            format!(
+                "Search stopped after {} results (--max-results); the results are incomplete",
+                max
+            )
+            .yellow()
+        );
+    }
+
+    start_pager(global);
+    if let Some(sha) = &commit {
+        let mut commit_presence: Vec<_> = searches
+            .iter()
+            .flat_map(|search| search.commit_presence.iter())
+            .collect();
+        commit_presence.sort_by(|(a, _), (b, _)| a.name_with_namespace.cmp(&b.name_with_namespace));
+
+        if format == OutputFormat::Json {
+            let json_presence: Vec<_> = commit_presence
+                .iter()
+                .map(|(project, present)| JsonCommitPresence {
+                    project: &project.path_with_namespace,
+                    project_id: project.id,
+                    present: *present,
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&json_presence)?);
+            return Ok(());
+        }
+
+        if !quiet {
+            println!();
+        }
+        for (project, present) in commit_presence.iter() {
+            if *present {
+                println!(
+                    "{}: {}",
+                    project_heading(project).green(),
+                    "present".green()
+                );
+            } else {
+                println!("{}: {}", project_heading(project).green(), "absent".red());
+            }
+        }
+        let present = commit_presence
+            .iter()
+            .filter(|(_, present)| *present)
+            .count();
+        println!(
+            "\nCommit {} is in {} of {} projects",
+            sha.cyan(),
+            present.to_string().yellow(),
+            commit_presence.len()
+        );
+        return Ok(());
+    }
+
+    if totals {
+        let mut project_totals: Vec<_> = searches
+            .iter()
+            .flat_map(|search| {
+                let instance = search.client.config().name.as_str();
+                search
+                    .totals
+                    .iter()
+                    .map(move |(project, scope, total)| (instance, project, scope, total))
+            })
+            .collect();
+        project_totals.sort_by(|(_, a, a_scope, a_total), (_, b, b_scope, b_total)| {
+            b_total
+                .cmp(a_total)
+                .then_with(|| a.name_with_namespace.cmp(&b.name_with_namespace))
+                .then_with(|| a_scope.api_name().cmp(b_scope.api_name()))
+        });
+
+        if format == OutputFormat::Json {
+            let json_totals: Vec<_> = project_totals
+                .iter()
+                .map(|(_, project, scope, total)| JsonTotal {
+                    project: &project.path_with_namespace,
+                    project_id: project.id,
+                    scope: scope.api_name(),
+                    total: **total,
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&json_totals)?);
+            return Ok(());
+        }
+
+        let mut sum = 0;
+        let mut matching_projects = 0;
+        if !quiet {
+            println!();
+        }
+        let mut counted_projects = HashSet::new();
+        for (instance, project, scope, total) in project_totals.iter() {
+            let name = if label_scopes {
+                format!("{} [{}]", project_heading(project), scope.api_name())
+            } else {
+                project_heading(project)
+            };
+            match total {
+                Some(0) if show_empty => println!("{}: {}", name.dimmed(), 0),
+                Some(0) => continue,
+                Some(total) => {
+                    sum += total;
+                    println!("{}: {}", name.green(), total.to_string().yellow());
+                }
+                None => println!("{}: {}", name.green(), "unknown".red()),
+            }
+            if **total != Some(0) && counted_projects.insert((*instance, project.id)) {
+                matching_projects += 1;
+            }
+        }
+        println!(
+            "\nTotal: {} matches in {} projects",
+            sum.to_string().yellow(),
+            matching_projects
+        );
+        return Ok(());
+    }
+
+    let render_options = RenderOptions {
+        raw,
+        min_line_len,
+        max_line_len,
+    };
+
+    let from_cache = searches.iter().all(|search| search.from_cache);
+    let mut search_results: Vec<_> = searches
+        .iter()
+        .flat_map(|search| {
+            search
+                .results
+                .iter()
+                .map(move |(project, hit)| (search, project, hit))
+        })
+        .collect();
+    // Results whose every line is filtered out by length would print as empty headers.
+    search_results.retain(|(_, _, hit)| match hit {
+        SearchHit::Blob(SearchResultRaw { data, .. })
+        | SearchHit::WikiBlob(WikiBlobResult { data, .. }) => {
+            data.lines().any(|line| render_options.keeps_line(line))
+        }
+        SearchHit::MergeRequest { .. } | SearchHit::Issue(_) => true,
+    });
+    let mut removed = Vec::new();
+    if let Some(baseline) = &baseline {
+        let key = |project: &Project, result: &SearchResultRaw| -> BaselineKey {
+            (
+                project.path_with_namespace.clone(),
+                result.path.clone(),
+                result.startline,
+                result.data.clone(),
+            )
+        };
+        let current: HashSet<BaselineKey> = search_results
+            .iter()
+            .filter_map(|(_, project, hit)| match hit {
+                SearchHit::Blob(result) => Some(key(project, result)),
+                _ => None,
+            })
+            .collect();
+        if show_removed {
+            removed = baseline
+                .iter()
+                .filter(|(baseline_key, _)| !current.contains(baseline_key))
+                .collect();
+        }
+        let known: HashSet<&BaselineKey> = baseline.iter().map(|(key, _)| key).collect();
+        search_results.retain(|(_, project, hit)| match hit {
+            SearchHit::Blob(result) => !known.contains(&key(project, result)),
+            SearchHit::MergeRequest { .. } | SearchHit::Issue(_) | SearchHit::WikiBlob(_) => true,
+        });
+    }
+    // Results arrive in completion order, which varies from run to run.
+    if sorted_stable {
+        search_results.sort_by(|(a_search, a, a_hit), (b_search, b, b_hit)| {
+            a_search
+                .client
+                .config()
+                .name
+                .cmp(&b_search.client.config().name)
+                .then_with(|| a.path_with_namespace.cmp(&b.path_with_namespace))
+                .then_with(|| a_hit.sort_key().cmp(&b_hit.sort_key()))
+        });
+    }
+    if let Some(path) = &summary_json {
+        // Every searched project is listed, with 0 when it had no results.
+        let mut projects: Vec<JsonProjectCount> = searches
+            .iter()
+            .flat_map(|search| {
+                search.completed.iter().map(|project| JsonProjectCount {
+                    instance: search.client.config().name.as_str(),
+                    project: &project.path_with_namespace,
+                    project_id: project.id,
+                    results: 0,
+                })
+            })
+            .collect();
+        for (search, project, _) in &search_results {
+            let instance = search.client.config().name.as_str();
+            match projects
+                .iter_mut()
+                .find(|count| count.instance == instance && count.project_id == project.id)
+            {
+                Some(count) => count.results += 1,
+                None => projects.push(JsonProjectCount {
+                    instance,
+                    project: &project.path_with_namespace,
+                    project_id: project.id,
+                    results: 1,
+                }),
+            }
+        }
+        projects.sort_by(|a, b| b.results.cmp(&a.results).then(a.project.cmp(b.project)));
+        let summary = JsonSummary {
+            query: &query,
+            instances: searches
+                .iter()
+                .map(|search| search.client.config().name.as_str())
+                .collect(),
+            total_results: search_results.len(),
+            projects,
+            duration_secs: started.elapsed().as_secs_f64(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&summary)?)
+            .with_context(|| format!("Failed to write summary {}", path.display()))?;
+    }
+    if format == OutputFormat::Json {
+        let mut json_results = search_results
+            .iter()
+            .map(|(search, project, hit)| {
+                let mut value = serde_json::to_value(JsonHit::new(project, hit, include_data))?;
+                value["instance"] = search.client.config().name.as_str().into();
+                let located = match hit {
+                    SearchHit::Blob(SearchResultRaw {
+                        data, startline, ..
+                    })
+                    | SearchHit::WikiBlob(WikiBlobResult {
+                        data, startline, ..
+                    }) if args.column => locate_match(data, &query, &render_options)
+                        .map(|(offset, column)| (startline + offset as u64, column)),
+                    _ => None,
+                };
+                if let Some((line, column)) = located {
+                    value["line"] = line.into();
+                    value["column"] = column.into();
+                }
+                Ok(value)
+            })
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        // Removed results are echoed from the baseline as they were saved.
+        for (_, entry) in &removed {
+            let mut entry = entry.clone();
+            entry["removed"] = serde_json::Value::Bool(true);
+            json_results.push(entry);
+        }
+        let Some(dir) = &output_dir else {
+            println!("{}", serde_json::to_string_pretty(&json_results)?);
+            return Ok(());
+        };
+        let mut project_results: BTreeMap<PathBuf, Vec<serde_json::Value>> = BTreeMap::new();
+        for ((search, project, _), json_result) in search_results.iter().zip(json_results) {
+            project_results
+                .entry(project_output_path(
+                    dir,
+                    (searches.len() > 1).then(|| search.client.config().name.as_str()),
+                    &project.path_with_namespace,
+                    "json",
+                ))
+                .or_default()
+                .push(json_result);
+        }
+        let output_files = project_results
+            .into_iter()
+            .map(|(path, results)| Ok((path, serde_json::to_string_pretty(&results)?)))
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        write_output_files(&output_files)?;
+        return Ok(());
+    }
+
+    if format == OutputFormat::Markdown {
+        let multi_instance = searches.len() > 1;
+        let Some(dir) = &output_dir else {
+            print!(
+                "{}",
+                render_markdown(&query, &search_results, multi_instance, &render_options)?
+            );
+            return Ok(());
+        };
+        let mut project_results: BTreeMap<PathBuf, Vec<_>> = BTreeMap::new();
+        for &(search, project, hit) in &search_results {
+            project_results
+                .entry(project_output_path(
+                    dir,
+                    multi_instance.then(|| search.client.config().name.as_str()),
+                    &project.path_with_namespace,
+                    "md",
+                ))
+                .or_default()
+                .push((search, project, hit));
+        }
+        let output_files = project_results
+            .into_iter()
+            .map(|(path, results)| {
+                let document = render_markdown(&query, &results, multi_instance, &render_options)?;
+                Ok((path, document))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        write_output_files(&output_files)?;
+        return Ok(());
+    }
+
+    // Fetch each matched file once, however many matches it has, for both previews and context.
+    let matched_files: HashMap<(&str, u64, &str, &str), &InstanceSearch> = search_results
+        .iter()
+        .filter_map(|(search, project, hit)| match hit {
+            SearchHit::Blob(result) => Some((
+                (
+                    search.client.config().name.as_str(),
+                    project.id,
+                    result.path.as_str(),
+                    result.ref_field.as_str(),
+                ),
+                *search,
+            )),
+            _ => None,
+        })
+        .collect();
+    let mut file_contents = HashMap::new();
+    if preview_head.is_some() || context.is_some() {
+        let fetches = matched_files.iter().map(|(&file, &search)| {
+            async move {
+                let _permit = search.semaphore.acquire().await?;
+                let (_, project_id, path, git_ref) = file;
+                search.client.raw_file(project_id, path, git_ref).await
+            }
+            .map(move |contents| (file, contents))
+        });
+        file_contents.extend(join_all(fetches).await);
+    }
+    // Blame is fetched once per matched file, like the file contents.
+    let mut blames = HashMap::new();
+    if blame {
+        let fetches = matched_files.iter().map(|(&file, &search)| {
+            async move {
+                let _permit = search.semaphore.acquire().await?;
+                let (_, project_id, path, git_ref) = file;
+                search.client.blame(project_id, path, git_ref).await
+            }
+            .map(move |ranges| (file, ranges))
+        });
+        blames.extend(join_all(fetches).await);
+    }
+
+    let projects: HashSet<(&str, u64)> = search_results
+        .iter()
+        .map(|(search, project, _)| (search.client.config().name.as_str(), project.id))
+        .collect();
+    if !quiet {
+        let files: HashSet<(&str, u64, &str)> = search_results
+            .iter()
+            .filter_map(|(search, project, hit)| match hit {
+                SearchHit::Blob(result) => Some((
+                    search.client.config().name.as_str(),
+                    project.id,
+                    result.path.as_str(),
+                )),
+                _ => None,
+            })
+            .collect();
+        // Merge request and issue hits have no file to count.
+        let files = if files.is_empty() {
+            String::new()
+        } else {
+            format!(" in {} files", files.len())
+        };
+        println!(
+            "\nFound {}{} results{} across {} projects{}:",
+            search_results.len(),
+            if baseline.is_some() { " new" } else { "" },
+            files,
+            projects.len(),
+            if from_cache { " (cached)" } else { "" }
+        );
+    }
+
+    let renderer = HitRenderer {
+        compact_query: args.compact.then_some(query.as_str()),
+        column: args.column,
+        label_instances: searches.len() > 1,
+        label_scopes,
+        template: template.as_deref(),
+        render_options: &render_options,
+        preview_head,
+        context,
+        file_contents: &file_contents,
+        blames: &blames,
+        omit_project: args.relative_paths && projects.len() == 1,
+    };
+    let mut output_files: BTreeMap<PathBuf, String> = BTreeMap::new();
+    for (search, project, hit) in search_results.iter() {
+        let instance = search.client.config().name.as_str();
+        let rendered = renderer.render(instance, project, hit)?;
+        match &output_dir {
+            Some(dir) => output_files
+                .entry(project_output_path(
+                    dir,
+                    (searches.len() > 1).then_some(instance),
+                    &project.path_with_namespace,
+                    "txt",
+                ))
+                .or_default()
+                .push_str(&rendered),
+            None => print!("{}", rendered),
+        }
+    }
+    if let Some(dir) = &output_dir {
+        write_output_files(&output_files)?;
+        if !quiet {
+            println!(
+                "\nWrote results for {} projects to {}",
+                output_files.len(),
+                dir.display()
+            );
+        }
+    }
+
+    if show_removed {
+        println!("\nRemoved since the baseline ({}):", removed.len());
+        for ((project, path, startline, _), _) in &removed {
+            println!("  {} - {}:{}", project.red(), path, startline);
+        }
+    }
+
+    if show_empty {
+        let matched: HashSet<(&str, u64)> = search_results
+            .iter()
+            .map(|(search, project, _)| (search.client.config().name.as_str(), project.id))
+            .collect();
+        let mut empty: Vec<_> = searches
+            .iter()
+            .flat_map(|search| {
+                let instance = search.client.config().name.as_str();
+                search
+                    .completed
+                    .iter()
+                    .filter(|project| !matched.contains(&(instance, project.id)))
+                    .map(project_heading)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        empty.sort();
+        println!("\nProjects without matches ({}):", empty.len());
+        for name in empty {
+            println!("  {}", name.dimmed());
+        }
+    }
+
+    if by_namespace {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, project, _) in &search_results {
+            *counts.entry(&project.namespace.full_path).or_default() += 1;
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        let width = counts.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        println!("\nMatches by namespace:");
+        for (namespace, count) in counts {
+            println!(
+                "  {:<width$}  {}",
+                namespace.green(),
+                count.to_string().yellow(),
+                width = width
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// An error as printed to stderr by commands run with `--format json`.
+#[derive(Serialize)]
+struct JsonError {
+    error: String,
+    /// The underlying causes, outermost first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+}
+
+impl From<&anyhow::Error> for JsonError {
+    fn from(error: &anyhow::Error) -> Self {
+        let causes: Vec<String> = error.chain().skip(1).map(|e| e.to_string()).collect();
+        Self {
+            error: error.to_string(),
+            context: (!causes.is_empty()).then(|| causes.join(": ")),
+        }
+    }
+}
+
+impl Commands {
+    fn json_output(&self) -> bool {
+        match self {
+            Commands::Search(args) => args.format == OutputFormat::Json,
+            Commands::Projects(args) => args.format == OutputFormat::Json,
+            Commands::Config(_)
+            | Commands::Validate
+            | Commands::Ping(_)
+            | Commands::Index(_)
+            | Commands::Doctor(_)
+            | Commands::Bench(_) => false,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Runs alongside the command; its hint is only printed if it is ready by the end.
+    let update_check = update_check_path(&cli.global).map(|path| tokio::spawn(update_hint(path)));
+    let result = match &cli.global.log_file {
+        Some(path) => open_log_file(path),
+        None => Ok(()),
+    };
+    let result = match result {
+        Ok(()) => run(&cli).await,
+        Err(e) => Err(e),
+    };
+    finish_pager();
+    if let Err(e) = &result {
+        log_event("error", serde_json::json!({ "error": format!("{:#}", e) }));
+    }
+    if let Some(warning) = deprecation_warning() {
+        eprintln!("{}", warning.yellow());
+    }
+    if let Some(update_check) = update_check {
+        if update_check.is_finished() {
+            if let Ok(Some(hint)) = update_check.await {
+                eprintln!("{}", hint.yellow());
+            }
+        } else {
+            update_check.abort();
+        }
+    }
+
+    match result {
+        // Scripts consuming JSON get errors in the same form, instead of prose.
+        Err(e) if cli.command.json_output() => {
+            eprintln!("{}", serde_json::to_string(&JsonError::from(&e))?);
+            std::process::exit(1);
+        }
+        // anyhow's Debug output lists every cause and includes the captured backtrace.
+        Err(e) if cli.global.verbose_errors => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+async fn run(cli: &Cli) -> Result<()> {
     match &cli.command {
-        Commands::Config {
-            name,
-            url,
-            token,
-            list,
-        } => {
-            handle_config_command(name.clone(), url.clone(), token.clone(), *list).await?;
-        }
-        Commands::Search {
-            query,
-            instance,
-            project,
-            all_projects,
-        } => {
-            handle_search_command(
-                query.clone(),
-                instance.clone(),
-                project.clone(),
-                *all_projects,
-            )
-            .await?;
+        Commands::Config(args) => {
+            handle_config_command(args.clone(), &cli.global).await?;
+        }
+        Commands::Search(args) => {
+            handle_search_command(args.clone(), &cli.global).await?;
+        }
+        Commands::Projects(args) => {
+            handle_projects_command(args.clone(), &cli.global).await?;
+        }
+        Commands::Validate => {
+            handle_validate_command(&cli.global).await?;
+        }
+        Commands::Ping(args) => {
+            handle_ping_command(args.clone(), &cli.global).await?;
+        }
+        Commands::Doctor(args) => {
+            handle_doctor_command(args.clone(), &cli.global).await?;
         }
-        Commands::Projects { instance, archived } => {
-            handle_projects_command(instance.clone(), *archived).await?;
+        Commands::Bench(args) => {
+            handle_bench_command(args.clone(), &cli.global).await?;
+        }
+        Commands::Index(args) => {
+            handle_index_command(args.clone(), &cli.global).await?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_base_falls_back_without_a_config_dir() {
+        let cwd =
+            std::env::temp_dir().join(format!("gitlab-search-cli-cwd-{}", std::process::id()));
+        std::fs::create_dir_all(&cwd).unwrap();
+
+        assert_eq!(
+            config_base_from(None, Some(PathBuf::from("/xdg")), Some(cwd.clone())),
+            (PathBuf::from("/xdg/gitlab-search-cli"), true)
+        );
+        // A relative `$XDG_CONFIG_HOME` is ignored, as the XDG spec requires.
+        assert_eq!(
+            config_base_from(None, Some(PathBuf::from("xdg")), Some(cwd.clone())),
+            (std::env::temp_dir().join("gitlab-search-cli"), false)
+        );
+        std::fs::create_dir(cwd.join(".gitlab-search-cli")).unwrap();
+        let in_cwd = config_base_from(None, None, Some(cwd.clone()));
+        std::fs::remove_dir_all(&cwd).ok();
+        assert_eq!(in_cwd, (cwd.join(".gitlab-search-cli"), true));
+    }
+}
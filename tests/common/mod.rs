@@ -0,0 +1,98 @@
+// Each test crate uses its own subset of these helpers.
+#![allow(dead_code)]
+
+use gitlab_search_cli::gitlab::{GitLabClient, GitLabConfig};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use wiremock::MockServer;
+
+/// A project as GitLab lists it, in group `group`.
+pub fn project_json(id: u64) -> Value {
+    json!({
+        "id": id,
+        "description": null,
+        "name": format!("project-{}", id),
+        "name_with_namespace": format!("Group / project-{}", id),
+        "path": format!("project-{}", id),
+        "path_with_namespace": format!("group/project-{}", id),
+        "created_at": "2024-01-01T00:00:00Z",
+        "web_url": format!("https://gitlab.example.com/group/project-{}", id),
+        "last_activity_at": "2024-01-02T00:00:00Z",
+        "namespace": {
+            "id": 1,
+            "name": "Group",
+            "path": "group",
+            "kind": "group",
+            "full_path": "group",
+            "parent_id": null,
+            "web_url": "https://gitlab.example.com/groups/group"
+        }
+    })
+}
+
+/// A client for `server`, authenticating with the token `secret`.
+pub fn client_for(server: &MockServer) -> GitLabClient {
+    GitLabClient::new(
+        reqwest::Client::new(),
+        GitLabConfig {
+            name: "test".to_string(),
+            url: server.uri(),
+            token: "secret".to_string(),
+            ..Default::default()
+        },
+    )
+}
+
+/// Writes a config with `instances` to a fresh config home and returns its path.
+pub fn config_home(test: &str, instances: Value) -> PathBuf {
+    let home = std::env::temp_dir().join(format!(
+        "gitlab-search-cli-test-{}-{}",
+        test,
+        std::process::id()
+    ));
+    let dir = home.join("gitlab-search-cli");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("config.json"),
+        json!({ "gitlab_instances": instances }).to_string(),
+    )
+    .unwrap();
+    home
+}
+
+/// Runs the CLI with `home` as its config home and `env` on top of the instance-selecting
+/// variables cleared, whether or not it succeeds.
+pub async fn run_cli_with_env(
+    home: &Path,
+    args: &[&str],
+    env: &[(&str, &str)],
+) -> std::process::Output {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_gitlab-search-cli"))
+        .args(args)
+        .arg("--no-update-check")
+        .env("XDG_CONFIG_HOME", home)
+        .env_remove("GITLAB_INSTANCE")
+        .env_remove("GITLAB_URL")
+        .env_remove("GITLAB_TOKEN")
+        .envs(env.iter().copied())
+        .output()
+        .await
+        .unwrap()
+}
+
+/// Runs the CLI with `home` as its config home, whether or not it succeeds.
+pub async fn run_cli_output(home: &Path, args: &[&str]) -> std::process::Output {
+    run_cli_with_env(home, args, &[]).await
+}
+
+/// Runs the CLI with `home` as its config home and returns its stdout.
+pub async fn run_cli(home: &Path, args: &[&str]) -> String {
+    let output = run_cli_output(home, args).await;
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
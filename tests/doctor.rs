@@ -0,0 +1,78 @@
+mod common;
+
+use common::{config_home, run_cli, run_cli_output};
+use serde_json::json;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn mount_instance(server: &MockServer, scopes: &[&str]) {
+    Mock::given(method("GET"))
+        .and(path("/api/v4/version"))
+        .and(header("PRIVATE-TOKEN", "secret"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "version": "16.0.0", "revision": "abc" })),
+        )
+        .mount(server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/v4/personal_access_tokens/self"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "name": "ci", "scopes": scopes })),
+        )
+        .mount(server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/v4/search"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn doctor_passes_a_reachable_instance_with_a_read_api_token() {
+    let server = MockServer::start().await;
+    mount_instance(&server, &["read_api"]).await;
+    let home = config_home(
+        "doctor-ok",
+        json!([{ "name": "test", "url": server.uri(), "token": "secret" }]),
+    );
+
+    let stdout = run_cli(&home, &["doctor"]).await;
+    std::fs::remove_dir_all(&home).ok();
+
+    assert!(stdout.contains("reachable, GitLab 16.0.0"), "{}", stdout);
+    assert!(
+        stdout.contains("token 'ci' is valid with scopes read_api"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("advanced search is enabled"), "{}", stdout);
+    assert!(stdout.contains("Everything looks good"), "{}", stdout);
+}
+
+#[tokio::test]
+async fn doctor_fails_a_token_without_read_api() {
+    let server = MockServer::start().await;
+    mount_instance(&server, &["read_user"]).await;
+    let home = config_home(
+        "doctor-scopes",
+        json!([{ "name": "test", "url": server.uri(), "token": "secret" }]),
+    );
+
+    let output = run_cli_output(&home, &["doctor"]).await;
+    std::fs::remove_dir_all(&home).ok();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("token 'ci' has scopes read_user; searching needs 'read_api'"),
+        "{}",
+        stdout
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("1 check failed"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
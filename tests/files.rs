@@ -0,0 +1,26 @@
+mod common;
+
+use common::client_for;
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn file_paths_are_encoded_as_a_single_segment() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(
+            "/api/v4/projects/1/repository/files/docs%2Fissue%20%231.md/raw",
+        ))
+        .and(query_param("ref", "main"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("contents"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let contents = client_for(&server)
+        .raw_file(1, "docs/issue #1.md", "main")
+        .await
+        .unwrap();
+
+    assert_eq!(contents, "contents");
+}
@@ -0,0 +1,206 @@
+mod common;
+
+use common::{config_home, project_json, run_cli, run_cli_with_env};
+use gitlab_search_cli::gitlab::{GitLabClient, GitLabConfig, ProjectListOptions};
+use serde_json::{json, Value};
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn instance(name: &str, url: &str, token: &str) -> GitLabConfig {
+    GitLabConfig {
+        name: name.to_string(),
+        url: url.to_string(),
+        token: token.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Serves a distinct project list per token, each with its own ETag. Revalidation
+/// answers 304 for any request carrying that ETag, whatever its token, so a cache
+/// entry shared between instances would hand one instance the other's projects.
+async fn mount_projects_for_token(server: &MockServer, token: &str, project_id: u64) {
+    Mock::given(method("GET"))
+        .and(path("/api/v4/projects"))
+        .and(header("If-None-Match", format!("\"{}\"", token).as_str()))
+        .respond_with(ResponseTemplate::new(304))
+        .with_priority(1)
+        .mount(server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/v4/projects"))
+        .and(header("PRIVATE-TOKEN", token))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("ETag", format!("\"{}\"", token))
+                .insert_header("X-Next-Page", "")
+                .set_body_json(json!([project_json(project_id)])),
+        )
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn instances_sharing_a_url_use_their_own_tokens() {
+    let server = MockServer::start().await;
+    mount_projects_for_token(&server, "personal-token", 1).await;
+    mount_projects_for_token(&server, "service-token", 2).await;
+
+    let cache_path = std::env::temp_dir().join(format!(
+        "gitlab-search-cli-test-cache-{}.json",
+        std::process::id()
+    ));
+    let personal = GitLabClient::new(
+        reqwest::Client::new(),
+        instance("personal", &server.uri(), "personal-token"),
+    )
+    .with_project_cache(cache_path.clone());
+    let service = GitLabClient::new(
+        reqwest::Client::new(),
+        instance("service", &server.uri(), "service-token"),
+    )
+    .with_project_cache(cache_path.clone());
+
+    // Run twice so the second round goes through the shared ETag cache file.
+    for _ in 0..2 {
+        let personal_projects = personal
+            .projects(&ProjectListOptions::default())
+            .await
+            .unwrap();
+        let service_projects = service
+            .projects(&ProjectListOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            personal_projects.iter().map(|p| p.id).collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert_eq!(
+            service_projects.iter().map(|p| p.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    std::fs::remove_file(cache_path).ok();
+}
+
+fn shared_url_instances(url: &str) -> Value {
+    json!([
+        { "name": "personal", "url": url, "token": "personal-token" },
+        { "name": "service", "url": url, "token": "service-token" },
+    ])
+}
+
+#[tokio::test]
+async fn rotating_tokens_cache_project_pages_per_token() {
+    let server = MockServer::start().await;
+    mount_projects_for_token(&server, "first-token", 1).await;
+    mount_projects_for_token(&server, "second-token", 2).await;
+
+    let cache_path = std::env::temp_dir().join(format!(
+        "gitlab-search-cli-test-rotation-cache-{}.json",
+        std::process::id()
+    ));
+    let client = GitLabClient::new(
+        reqwest::Client::new(),
+        GitLabConfig {
+            tokens: vec!["first-token".to_string(), "second-token".to_string()],
+            ..instance("rotating", &server.uri(), "")
+        },
+    )
+    .with_project_cache(cache_path.clone());
+
+    // Each listing takes the next token; the second round revalidates through the cache.
+    for _ in 0..2 {
+        for expected in [1, 2] {
+            let projects = client
+                .projects(&ProjectListOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                projects.iter().map(|p| p.id).collect::<Vec<_>>(),
+                vec![expected]
+            );
+        }
+    }
+
+    std::fs::remove_file(cache_path).ok();
+}
+
+#[tokio::test]
+async fn instance_flag_selects_by_name_among_instances_sharing_a_url() {
+    let server = MockServer::start().await;
+    mount_projects_for_token(&server, "personal-token", 1).await;
+    mount_projects_for_token(&server, "service-token", 2).await;
+    let home = config_home("select", shared_url_instances(&server.uri()));
+
+    // Without --instance the first entry is used; with it, the named one, not the first
+    // entry with that URL.
+    let default_projects = run_cli(&home, &["projects"]).await;
+    let service_projects = run_cli(&home, &["projects", "--instance", "service"]).await;
+
+    assert!(default_projects.contains("(ID: 1)"), "{}", default_projects);
+    assert!(
+        !default_projects.contains("(ID: 2)"),
+        "{}",
+        default_projects
+    );
+    assert!(service_projects.contains("(ID: 2)"), "{}", service_projects);
+    assert!(
+        !service_projects.contains("(ID: 1)"),
+        "{}",
+        service_projects
+    );
+
+    std::fs::remove_dir_all(home).ok();
+}
+
+#[tokio::test]
+async fn config_list_shows_the_url_of_each_instance() {
+    let home = config_home("list", shared_url_instances("https://gitlab.example.com"));
+
+    let listing = run_cli(&home, &["config", "--list"]).await;
+
+    assert!(
+        listing.contains("personal") && listing.contains("service"),
+        "{}",
+        listing
+    );
+    assert_eq!(
+        listing.matches(" - https://gitlab.example.com").count(),
+        2,
+        "{}",
+        listing
+    );
+
+    std::fs::remove_dir_all(home).ok();
+}
+
+#[tokio::test]
+async fn gitlab_url_and_token_from_the_environment_need_no_config() {
+    let server = MockServer::start().await;
+    mount_projects_for_token(&server, "env-token", 7).await;
+    let home = std::env::temp_dir().join(format!(
+        "gitlab-search-cli-test-env-only-{}",
+        std::process::id()
+    ));
+
+    let output = run_cli_with_env(
+        &home,
+        &["projects"],
+        &[
+            ("GITLAB_URL", server.uri().as_str()),
+            ("GITLAB_TOKEN", "env-token"),
+        ],
+    )
+    .await;
+    std::fs::remove_dir_all(&home).ok();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("group/project-7"), "{}", stdout);
+}
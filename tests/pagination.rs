@@ -0,0 +1,206 @@
+mod common;
+
+use common::{client_for, project_json};
+use gitlab_search_cli::gitlab::ProjectListOptions;
+use serde_json::json;
+use wiremock::matchers::{header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn projects_page(page: &str) -> wiremock::MockBuilder {
+    Mock::given(method("GET"))
+        .and(path("/api/v4/projects"))
+        .and(header("PRIVATE-TOKEN", "secret"))
+        .and(query_param("page", page))
+}
+
+#[tokio::test]
+async fn follows_next_page_header_until_it_is_empty() {
+    let server = MockServer::start().await;
+
+    projects_page("1")
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Next-Page", "2")
+                .set_body_json(json!([project_json(1), project_json(2)])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("2")
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Next-Page", "")
+                .set_body_json(json!([project_json(3)])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("3")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let projects = client_for(&server)
+        .projects(&ProjectListOptions::default())
+        .await
+        .unwrap();
+
+    let ids: Vec<u64> = projects.iter().map(|p| p.id).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn stops_on_empty_final_page_without_next_page_header() {
+    let server = MockServer::start().await;
+
+    projects_page("1")
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!([project_json(1), project_json(2)])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("2")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([project_json(3)])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("3")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("4")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([project_json(4)])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let projects = client_for(&server)
+        .projects(&ProjectListOptions::default())
+        .await
+        .unwrap();
+
+    let ids: Vec<u64> = projects.iter().map(|p| p.id).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn stops_on_empty_page_even_when_next_page_header_is_set() {
+    let server = MockServer::start().await;
+
+    projects_page("1")
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Next-Page", "2")
+                .set_body_json(json!([project_json(1)])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("2")
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Next-Page", "3")
+                .set_body_json(json!([])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("3")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([project_json(3)])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let projects = client_for(&server)
+        .projects(&ProjectListOptions::default())
+        .await
+        .unwrap();
+
+    let ids: Vec<u64> = projects.iter().map(|p| p.id).collect();
+    assert_eq!(ids, vec![1]);
+}
+
+#[tokio::test]
+async fn refetches_a_page_answered_not_modified_without_a_cached_copy() {
+    let server = MockServer::start().await;
+
+    projects_page("1")
+        .respond_with(ResponseTemplate::new(304))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    projects_page("1")
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Next-Page", "")
+                .set_body_json(json!([project_json(1)])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let projects = client_for(&server)
+        .projects(&ProjectListOptions::default())
+        .await
+        .unwrap();
+
+    let ids: Vec<u64> = projects.iter().map(|p| p.id).collect();
+    assert_eq!(ids, vec![1]);
+}
+
+#[tokio::test]
+async fn archived_listings_ask_for_active_archived_or_all_projects() {
+    let server = MockServer::start().await;
+    for (archived, id) in [("false", 1), ("true", 2)] {
+        projects_page("1")
+            .and(query_param("archived", archived))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("X-Next-Page", "")
+                    .set_body_json(json!([project_json(id)])),
+            )
+            .mount(&server)
+            .await;
+    }
+    projects_page("1")
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Next-Page", "")
+                .set_body_json(json!([project_json(1), project_json(2)])),
+        )
+        .with_priority(10)
+        .mount(&server)
+        .await;
+
+    let client = client_for(&server);
+    let ids = |projects: Vec<gitlab_search_cli::gitlab::Project>| {
+        projects.iter().map(|p| p.id).collect::<Vec<_>>()
+    };
+    let active = client
+        .projects(&ProjectListOptions::default())
+        .await
+        .unwrap();
+    let archived = client
+        .projects(&ProjectListOptions {
+            only_archived: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let all = client
+        .projects(&ProjectListOptions {
+            include_archived: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(ids(active), vec![1]);
+    assert_eq!(ids(archived), vec![2]);
+    assert_eq!(ids(all), vec![1, 2]);
+}
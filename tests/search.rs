@@ -0,0 +1,144 @@
+mod common;
+
+use common::{config_home, project_json, run_cli};
+use serde_json::json;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn mount_json(server: &MockServer, route: &str, body: serde_json::Value) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn matches_reported_for_another_project_are_labelled_with_it() {
+    let server = MockServer::start().await;
+    mount_json(
+        &server,
+        "/api/v4/version",
+        json!({ "version": "16.0.0", "revision": "abc" }),
+    )
+    .await;
+    mount_json(&server, "/api/v4/projects/1", project_json(1)).await;
+    mount_json(&server, "/api/v4/projects/3", project_json(3)).await;
+    mount_json(
+        &server,
+        "/api/v4/projects/1/search",
+        json!([{
+            "basename": "main",
+            "data": "hello\n",
+            "path": "src/main.rs",
+            "filename": "src/main.rs",
+            "id": null,
+            "ref": "main",
+            "startline": 1,
+            "project_id": 3
+        }]),
+    )
+    .await;
+
+    let home = config_home(
+        "relabel",
+        json!([{ "name": "test", "url": server.uri(), "token": "secret" }]),
+    );
+
+    let stdout = run_cli(
+        &home,
+        &[
+            "search",
+            "--query",
+            "hello",
+            "--project",
+            "1",
+            "--no-cache",
+            "--compact",
+        ],
+    )
+    .await;
+    std::fs::remove_dir_all(&home).ok();
+
+    assert!(
+        stdout.contains("group/project-3:src/main.rs:1:"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("project-1"), "{}", stdout);
+}
+
+#[tokio::test]
+async fn summary_json_counts_searched_projects_without_results() {
+    let server = MockServer::start().await;
+    mount_json(
+        &server,
+        "/api/v4/version",
+        json!({ "version": "16.0.0", "revision": "abc" }),
+    )
+    .await;
+    for id in [1, 2] {
+        mount_json(
+            &server,
+            &format!("/api/v4/projects/{}", id),
+            project_json(id),
+        )
+        .await;
+    }
+    mount_json(
+        &server,
+        "/api/v4/projects/1/search",
+        json!([{
+            "basename": "main",
+            "data": "hello\n",
+            "path": "src/main.rs",
+            "filename": "src/main.rs",
+            "id": null,
+            "ref": "main",
+            "startline": 1,
+            "project_id": 1
+        }]),
+    )
+    .await;
+    mount_json(&server, "/api/v4/projects/2/search", json!([])).await;
+
+    let home = config_home(
+        "summary",
+        json!([{ "name": "test", "url": server.uri(), "token": "secret" }]),
+    );
+    let summary_path = home.join("summary.json");
+
+    run_cli(
+        &home,
+        &[
+            "search",
+            "--query",
+            "hello",
+            "--project",
+            "1",
+            "--project",
+            "2",
+            "--no-cache",
+            "--summary-json",
+            summary_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&summary_path).unwrap()).unwrap();
+    std::fs::remove_dir_all(&home).ok();
+
+    let counts: Vec<(&str, u64)> = summary["projects"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|count| {
+            (
+                count["project"].as_str().unwrap(),
+                count["results"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(counts, vec![("group/project-1", 1), ("group/project-2", 0)]);
+    assert_eq!(summary["total_results"], 1);
+}